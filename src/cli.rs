@@ -1,6 +1,6 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
 use crate::conf::ExternalConfig;
@@ -16,4 +16,40 @@ pub struct Cli {
     pub conf_dir: Option<PathBuf>,
     #[command(flatten)]
     pub config: ExternalConfig,
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// manage unseal keys in the kernel keyring
+    #[command(subcommand)]
+    Keyring(KeyringCommand),
+    /// unseal every configured node once with key shares typed at a prompt
+    /// or piped on stdin, without using the key source
+    Manual {
+        /// discard the progress already on the nodes before submitting
+        #[arg(long)]
+        reset: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum KeyringCommand {
+    /// read unseal keys from stdin, one per line or a json array, or at a
+    /// prompt, and store them in the configured keyring
+    Load,
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn arguments_do_not_clash() {
+        Cli::command().debug_assert();
+    }
 }
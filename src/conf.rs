@@ -1,14 +1,18 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{collections::HashMap, fmt, path::PathBuf, str::FromStr};
 
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use clap::{Args, ValueEnum};
 use error_stack::{Report, ResultExt};
 use figment::{Figment, providers::*};
+use semver::VersionReq;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracing::Level;
-use url::Url;
+use url::{Host, Url};
 use uuid::Uuid;
 
+use crate::secret::{self, Secret};
+
 #[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 #[error("config error")]
@@ -19,49 +23,1663 @@ pub enum Error {
     InvalidVaultNodeUrl,
     #[error("missing bitwarden configuration")]
     MissingBitwardenConfig,
+    #[error("invalid bitwarden url")]
+    InvalidBitwardenUrl,
+    #[error("invalid tls configuration")]
+    InvalidTlsConfig,
+    #[error("invalid identity pins")]
+    InvalidIdentityPins,
+    #[error("invalid certificate pin")]
+    InvalidCertPin,
+    #[error("insecure vault node")]
+    InsecureVaultNode,
+    #[error("insecure key source url")]
+    InsecureSourceUrl,
+    #[error("invalid key source configuration")]
+    InvalidSourceConfig,
+}
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct VaultNode {
+    pub host: Url,
+    /// overrides the global identity pins for this node
+    #[serde(default)]
+    pub identity: ClusterIdentity,
+    /// overrides the global tls settings for this node
+    #[serde(default)]
+    pub tls: Tls,
+    /// accepted certificate or public key pins, any match is enough
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pins: Vec<CertPin>,
+    /// overrides the global `allow_insecure_http` for this node
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_insecure_http: Option<bool>,
+}
+
+impl VaultNode {
+    // Unseal keys must not be sent in cleartext unless explicitly allowed
+    fn validate_scheme(&self, allow_insecure_http: bool, allow_loopback_http: bool) -> Result<()> {
+        match self.host.scheme() {
+            "https" => Ok(()),
+            "http" if self.allow_insecure_http.unwrap_or(allow_insecure_http) => Ok(()),
+            "http" if allow_loopback_http && self.is_loopback() => Ok(()),
+            "http" => {
+                let report = Report::new(Error::InsecureVaultNode).attach(format!(
+                    "{} uses plaintext http, set allow_insecure_http to send unseal keys in cleartext",
+                    self.host
+                ));
+                Err(report)
+            }
+            scheme => {
+                let report = Report::new(Error::InvalidVaultNodeUrl).attach(format!(
+                    "unsupported scheme {scheme} for {}, vault nodes are reached over http or https, unix sockets are not supported",
+                    self.host
+                ));
+                Err(report)
+            }
+        }
+    }
+
+    // Only tcp loopback addresses, the vault client cannot reach unix sockets
+    fn is_loopback(&self) -> bool {
+        match self.host.host() {
+            Some(Host::Domain(domain)) => domain == "localhost" || domain.ends_with(".localhost"),
+            Some(Host::Ipv4(ip)) => ip.is_loopback(),
+            Some(Host::Ipv6(ip)) => ip.is_loopback(),
+            None => false,
+        }
+    }
+}
+
+impl FromStr for VaultNode {
+    type Err = Report<Error>;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(VaultNode {
+            host: Url::parse(s).change_context(Error::InvalidVaultNodeUrl)?,
+            identity: ClusterIdentity::default(),
+            tls: Tls::default(),
+            pins: Vec::new(),
+            allow_insecure_http: None,
+        })
+    }
+}
+
+/// SHA-256 pin of a vault node's leaf certificate or public key
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum CertPin {
+    /// `spki-sha256:<base64>`, survives renewals that keep the key
+    Spki([u8; 32]),
+    /// `cert-sha256:<hex>`, colons are allowed
+    Cert([u8; 32]),
+}
+
+impl FromStr for CertPin {
+    type Err = Report<Error>;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || {
+            Report::new(Error::InvalidCertPin).attach(format!(
+                "expected spki-sha256:<base64> or cert-sha256:<hex>, got {s}"
+            ))
+        };
+
+        if let Some(hash) = s.strip_prefix("spki-sha256:") {
+            let hash = BASE64.decode(hash.trim()).map_err(|_| invalid())?;
+            return Ok(CertPin::Spki(hash.try_into().map_err(|_| invalid())?));
+        }
+
+        if let Some(hash) = s.strip_prefix("cert-sha256:") {
+            let hex: String = hash.chars().filter(|c| *c != ':').collect();
+            if hex.len() != 64 {
+                return Err(invalid());
+            }
+            let mut bytes = [0u8; 32];
+            for (byte, chunk) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+                let chunk = std::str::from_utf8(chunk).map_err(|_| invalid())?;
+                *byte = u8::from_str_radix(chunk, 16).map_err(|_| invalid())?;
+            }
+            return Ok(CertPin::Cert(bytes));
+        }
+
+        Err(invalid())
+    }
+}
+
+impl TryFrom<String> for CertPin {
+    type Error = Report<Error>;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for CertPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertPin::Spki(hash) => write!(f, "spki-sha256:{}", BASE64.encode(hash)),
+            CertPin::Cert(hash) => {
+                write!(f, "cert-sha256:")?;
+                hash.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+            }
+        }
+    }
+}
+
+impl From<CertPin> for String {
+    fn from(pin: CertPin) -> Self {
+        pin.to_string()
+    }
+}
+
+/// Expected identity of a vault node, checked against its seal status
+/// before any unseal key is sent. A sealed vault does not report its cluster
+/// id or name, so only the seal type and version are enforced while sealed,
+/// the cluster pins only catch a node that reports a different cluster and
+/// are refused without one of them
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ClusterIdentity {
+    /// expected vault cluster id, not reported while sealed
+    #[arg(long = "cluster-id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster_id: Option<String>,
+    /// expected vault cluster name, not reported while sealed
+    #[arg(long = "cluster-name")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cluster_name: Option<String>,
+    /// expected seal type, e.g. shamir
+    #[arg(long = "seal-type")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seal_type: Option<String>,
+    /// accepted vault version range, e.g. ">=1.15, <1.21"
+    #[arg(id = "vault_version", long = "vault-version")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<VersionReq>,
+}
+
+impl ClusterIdentity {
+    /// Fill every unset pin from `global`
+    pub fn or(self, global: &ClusterIdentity) -> Self {
+        Self {
+            cluster_id: self.cluster_id.or_else(|| global.cluster_id.clone()),
+            cluster_name: self.cluster_name.or_else(|| global.cluster_name.clone()),
+            seal_type: self.seal_type.or_else(|| global.seal_type.clone()),
+            version: self.version.or_else(|| global.version.clone()),
+        }
+    }
+
+    // A sealed vault never reports its cluster, so cluster pins alone would
+    // let keys reach any sealed node
+    fn validate(&self, host: &Url) -> Result<()> {
+        let cluster_pinned = self.cluster_id.is_some() || self.cluster_name.is_some();
+        if cluster_pinned && self.seal_type.is_none() && self.version.is_none() {
+            let report = Report::new(Error::InvalidIdentityPins).attach(format!(
+                "cluster_id and cluster_name are not checked while {host} is sealed, also pin seal_type or version"
+            ));
+            return Err(report);
+        }
+
+        Ok(())
+    }
+}
+
+/// TLS settings used to connect to vault nodes
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct Tls {
+    /// pem bundle of ca certificates trusted for vault nodes
+    #[arg(long = "tls-ca-file")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+    /// directory of pem ca certificates trusted for vault nodes
+    #[arg(long = "tls-ca-dir")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_dir: Option<PathBuf>,
+    /// pem client certificate chain for mtls
+    #[arg(long = "tls-client-cert")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_cert: Option<PathBuf>,
+    /// pem client private key for mtls
+    #[arg(long = "tls-client-key")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_key: Option<PathBuf>,
+    /// server name (sni) to verify when a node is addressed by ip
+    #[arg(long = "tls-server-name")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+    /// skip server certificate verification, unseal keys can be intercepted
+    #[arg(long = "tls-insecure-skip-verify")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub insecure_skip_verify: Option<bool>,
+}
+
+impl Tls {
+    /// Fill every unset setting from `global`
+    pub fn or(self, global: &Tls) -> Self {
+        Self {
+            ca_file: self.ca_file.or_else(|| global.ca_file.clone()),
+            ca_dir: self.ca_dir.or_else(|| global.ca_dir.clone()),
+            client_cert: self.client_cert.or_else(|| global.client_cert.clone()),
+            client_key: self.client_key.or_else(|| global.client_key.clone()),
+            server_name: self.server_name.or_else(|| global.server_name.clone()),
+            insecure_skip_verify: self.insecure_skip_verify.or(global.insecure_skip_verify),
+        }
+    }
+
+    fn validate(&self, host: &Url) -> Result<()> {
+        if self.client_cert.is_some() != self.client_key.is_some() {
+            let report = Report::new(Error::InvalidTlsConfig).attach(format!(
+                "client cert and client key must be set together for {host}"
+            ));
+            return Err(report);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ExternalBitwarden {
+    /// bitwarden host
+    #[clap(long = "bw-host")]
+    #[serde(rename = "host")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bw_host: Option<Url>,
+    /// bitwarden api url, derived from host if not set
+    #[clap(long = "bw-api-url")]
+    #[serde(rename = "api_url")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bw_api_url: Option<Url>,
+    /// bitwarden identity url, derived from host if not set
+    #[clap(long = "bw-identity-url")]
+    #[serde(rename = "identity_url")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bw_identity_url: Option<Url>,
+    /// bitwarden token
+    #[clap(long = "bw-token")]
+    #[serde(rename = "token")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "secret::serialize_exposed")]
+    pub bw_token: Option<Secret>,
+    /// bitwarden secret ids
+    #[clap(long = "bw-secret-ids", use_value_delimiter = true)]
+    #[serde(rename = "secret_ids")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bw_secret_ids: Option<Vec<Uuid>>,
+    /// encrypted cache of the keys, used only while bitwarden is unreachable
+    #[clap(long = "bw-cache-path")]
+    #[serde(rename = "cache_path")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bw_cache_path: Option<PathBuf>,
+    /// seconds a cached key is usable for default: 86400
+    #[clap(long = "bw-cache-max-age")]
+    #[serde(rename = "cache_max_age")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bw_cache_max_age: Option<u64>,
+    /// age identity file the cache is encrypted to
+    #[clap(long = "bw-cache-age-identity-file")]
+    #[serde(rename = "cache_age_identity_file")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bw_cache_age_identity_file: Option<PathBuf>,
+    /// user keyring key holding a base64 aes-256 key for the cache
+    #[clap(long = "bw-cache-keyring-key")]
+    #[serde(rename = "cache_keyring_key")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bw_cache_keyring_key: Option<String>,
+    /// systemd credential holding a base64 aes-256 key for the cache
+    #[clap(long = "bw-cache-credential")]
+    #[serde(rename = "cache_credential")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bw_cache_credential: Option<String>,
+}
+
+#[derive(Debug, Args, Clone, Deserialize, Serialize)]
+pub struct Bitwarden {
+    pub host: Url,
+    pub api_url: Url,
+    pub identity_url: Url,
+    pub token: Secret,
+    pub secret_ids: Vec<Uuid>,
+    #[arg(skip)]
+    #[serde(skip)]
+    pub cache: Option<KeyCache>,
+}
+
+/// Key the bitwarden key cache is encrypted with
+#[derive(Debug, Clone)]
+pub enum CacheKey {
+    Age(PathBuf),
+    Keyring(Keyring),
+    Credential(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyCache {
+    pub path: PathBuf,
+    pub max_age: u64,
+    pub key: CacheKey,
+}
+
+impl KeyCache {
+    fn from_external(bitwarden: &ExternalBitwarden) -> Result<Option<Self>> {
+        let Some(path) = bitwarden.bw_cache_path.clone() else {
+            return Ok(None);
+        };
+
+        let key = match (
+            &bitwarden.bw_cache_age_identity_file,
+            &bitwarden.bw_cache_keyring_key,
+            &bitwarden.bw_cache_credential,
+        ) {
+            (Some(identity), None, None) => CacheKey::Age(identity.clone()),
+            (None, Some(description), None) => CacheKey::Keyring(Keyring {
+                keyring: KeyringKind::User,
+                description: description.clone(),
+            }),
+            (None, None, Some(name)) => CacheKey::Credential(name.clone()),
+            _ => {
+                let report = Report::new(Error::InvalidSourceConfig).attach(
+                    "bitwarden cache needs exactly one of cache_age_identity_file, cache_keyring_key or cache_credential",
+                );
+                return Err(report);
+            }
+        };
+
+        Ok(Some(KeyCache {
+            path,
+            max_age: bitwarden.bw_cache_max_age.unwrap_or(86400),
+            key,
+        }))
+    }
+}
+
+impl Bitwarden {
+    // Derive the api and identity endpoints from the web vault host.
+    // The official clouds use dedicated subdomains, self-hosted servers
+    // (including Vaultwarden) serve them under `/api` and `/identity`.
+    fn endpoints(host: &Url) -> Result<(Url, Url)> {
+        let (api_url, identity_url) = match host.host_str() {
+            Some(domain @ ("vault.bitwarden.com" | "vault.bitwarden.eu")) => {
+                let domain = domain.trim_start_matches("vault.");
+                (
+                    format!("https://api.{domain}"),
+                    format!("https://identity.{domain}"),
+                )
+            }
+            _ => {
+                let base = host.as_str().trim_end_matches('/');
+                (format!("{base}/api"), format!("{base}/identity"))
+            }
+        };
+
+        let api_url = Url::parse(&api_url).change_context(Error::InvalidBitwardenUrl)?;
+        let identity_url = Url::parse(&identity_url).change_context(Error::InvalidBitwardenUrl)?;
+        Ok((api_url, identity_url))
+    }
+}
+
+impl TryFrom<ExternalBitwarden> for Bitwarden {
+    type Error = Report<Error>;
+
+    fn try_from(bitwarden: ExternalBitwarden) -> std::result::Result<Self, Self::Error> {
+        let cache = KeyCache::from_external(&bitwarden)?;
+
+        match (
+            bitwarden.bw_host,
+            bitwarden.bw_token,
+            bitwarden.bw_secret_ids,
+        ) {
+            (Some(host), Some(token), Some(secret_ids)) => {
+                let (api_url, identity_url) = Bitwarden::endpoints(&host)?;
+                Ok(Bitwarden {
+                    host,
+                    api_url: bitwarden.bw_api_url.unwrap_or(api_url),
+                    identity_url: bitwarden.bw_identity_url.unwrap_or(identity_url),
+                    token,
+                    secret_ids,
+                    cache,
+                })
+            }
+            (Some(_), _, Some(secret_ids)) => {
+                if secret_ids.is_empty() {
+                    let report = Report::new(Error::MissingBitwardenConfig)
+                        .attach("bitwarden secret ids cannot be empty");
+                    return Err(report);
+                }
+                let report = Report::new(Error::MissingBitwardenConfig)
+                    .attach("bitwarden token must be specified");
+                Err(report)
+            }
+            (Some(_), Some(_), None) => {
+                let report = Report::new(Error::MissingBitwardenConfig)
+                    .attach("bitwarden secret ids must be specified");
+                Err(report)
+            }
+            (Some(_), None, None) => {
+                let report = Report::new(Error::MissingBitwardenConfig)
+                    .attach("bitwarden token and secret ids must be specified");
+                Err(report)
+            }
+            (None, _, _) => {
+                let report = Report::new(Error::MissingBitwardenConfig)
+                    .attach("bitwarden host must be specified");
+                Err(report)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ExternalAgeFile {
+    /// age encrypted file holding one key per line or a json array
+    #[arg(long = "age-file")]
+    #[serde(rename = "path")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_path: Option<PathBuf>,
+    /// age identity file with X25519 keys
+    #[arg(long = "age-identity-file")]
+    #[serde(rename = "identity_file")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_identity_file: Option<PathBuf>,
+    /// file holding the age passphrase
+    #[arg(long = "age-passphrase-file")]
+    #[serde(rename = "passphrase_file")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_passphrase_file: Option<PathBuf>,
+    /// environment variable holding the age passphrase
+    #[arg(long = "age-passphrase-env")]
+    #[serde(rename = "passphrase_env")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_passphrase_env: Option<String>,
+}
+
+/// How the age file is decrypted
+#[derive(Debug, Clone)]
+pub enum AgeIdentity {
+    IdentityFile(PathBuf),
+    PassphraseFile(PathBuf),
+    PassphraseEnv(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct AgeFile {
+    pub path: PathBuf,
+    pub identity: AgeIdentity,
+}
+
+impl TryFrom<ExternalAgeFile> for AgeFile {
+    type Error = Report<Error>;
+
+    fn try_from(age: ExternalAgeFile) -> std::result::Result<Self, Self::Error> {
+        let Some(path) = age.age_path else {
+            let report =
+                Report::new(Error::InvalidSourceConfig).attach("age file path must be specified");
+            return Err(report);
+        };
+
+        let identity = match (
+            age.age_identity_file,
+            age.age_passphrase_file,
+            age.age_passphrase_env,
+        ) {
+            (Some(file), None, None) => AgeIdentity::IdentityFile(file),
+            (None, Some(file), None) => AgeIdentity::PassphraseFile(file),
+            (None, None, Some(var)) => AgeIdentity::PassphraseEnv(var),
+            _ => {
+                let report = Report::new(Error::InvalidSourceConfig).attach(
+                    "exactly one of age identity_file, passphrase_file or passphrase_env must be specified",
+                );
+                return Err(report);
+            }
+        };
+
+        Ok(AgeFile { path, identity })
+    }
+}
+
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ExternalSops {
+    /// sops encrypted yaml or json document
+    #[arg(long = "sops-file")]
+    #[serde(rename = "path")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sops_path: Option<PathBuf>,
+    /// json pointer to the keys in the document, e.g. /vault/unseal_keys
+    #[arg(long = "sops-key-path")]
+    #[serde(rename = "key_path")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sops_key_path: Option<String>,
+    /// age identity file, defaults to $SOPS_AGE_KEY_FILE
+    #[arg(long = "sops-age-identity-file")]
+    #[serde(rename = "age_identity_file")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sops_age_identity_file: Option<PathBuf>,
+    /// gnupg home used to decrypt pgp recipients
+    #[arg(long = "sops-gnupg-home")]
+    #[serde(rename = "gnupg_home")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sops_gnupg_home: Option<PathBuf>,
+    /// sops binary, looked up in PATH by default
+    #[arg(long = "sops-binary")]
+    #[serde(rename = "binary")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sops_binary: Option<PathBuf>,
+    /// seconds before sops is killed
+    #[arg(long = "sops-timeout")]
+    #[serde(rename = "timeout")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sops_timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Sops {
+    pub path: PathBuf,
+    pub key_path: String,
+    pub age_identity_file: Option<PathBuf>,
+    pub gnupg_home: Option<PathBuf>,
+    pub binary: PathBuf,
+    pub timeout: u64,
+}
+
+impl TryFrom<ExternalSops> for Sops {
+    type Error = Report<Error>;
+
+    fn try_from(sops: ExternalSops) -> std::result::Result<Self, Self::Error> {
+        let (Some(path), Some(key_path)) = (sops.sops_path, sops.sops_key_path) else {
+            let report = Report::new(Error::InvalidSourceConfig)
+                .attach("sops path and key_path must be specified");
+            return Err(report);
+        };
+
+        if !key_path.starts_with('/') {
+            let report = Report::new(Error::InvalidSourceConfig).attach(format!(
+                "sops key_path must be a json pointer like /unseal_keys, got {key_path}"
+            ));
+            return Err(report);
+        }
+
+        Ok(Sops {
+            path,
+            key_path,
+            age_identity_file: sops
+                .sops_age_identity_file
+                .or_else(|| std::env::var_os("SOPS_AGE_KEY_FILE").map(PathBuf::from)),
+            gnupg_home: sops.sops_gnupg_home,
+            binary: sops.sops_binary.unwrap_or_else(|| PathBuf::from("sops")),
+            timeout: sops.sops_timeout.unwrap_or(30),
+        })
+    }
+}
+
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ExternalExec {
+    /// command printing the keys to stdout, program followed by its arguments
+    #[arg(long = "exec-command", num_args = 1.., allow_hyphen_values = true)]
+    #[serde(rename = "command")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec_command: Option<Vec<String>>,
+    /// extra environment variables for the command, config file only
+    #[arg(skip)]
+    #[serde(rename = "env")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec_env: Option<HashMap<String, Secret>>,
+    /// working directory of the command
+    #[arg(long = "exec-working-dir")]
+    #[serde(rename = "working_dir")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec_working_dir: Option<PathBuf>,
+    /// seconds before the command is killed default: 30
+    #[arg(long = "exec-timeout")]
+    #[serde(rename = "timeout")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec_timeout: Option<u64>,
+    /// largest accepted output in bytes default: 65536
+    #[arg(long = "exec-max-output-bytes")]
+    #[serde(rename = "max_output_bytes")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec_max_output_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Exec {
+    pub command: Vec<String>,
+    pub env: HashMap<String, Secret>,
+    pub working_dir: Option<PathBuf>,
+    pub timeout: u64,
+    pub max_output_bytes: u64,
+}
+
+impl TryFrom<ExternalExec> for Exec {
+    type Error = Report<Error>;
+
+    fn try_from(exec: ExternalExec) -> std::result::Result<Self, Self::Error> {
+        let command = exec.exec_command.unwrap_or_default();
+        if command.is_empty() {
+            let report =
+                Report::new(Error::InvalidSourceConfig).attach("exec command must be specified");
+            return Err(report);
+        }
+
+        Ok(Exec {
+            command,
+            env: exec.exec_env.unwrap_or_default(),
+            working_dir: exec.exec_working_dir,
+            timeout: exec.exec_timeout.unwrap_or(30),
+            max_output_bytes: exec.exec_max_output_bytes.unwrap_or(64 * 1024),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VaultAuthMethod {
+    Token,
+    Approle,
+    Cert,
+}
+
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ExternalVaultSource {
+    /// address of the vault holding the unseal keys
+    #[arg(long = "vault-addr")]
+    #[serde(rename = "address")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_addr: Option<Url>,
+    /// auth method for the key vault default: token
+    #[arg(long = "vault-auth")]
+    #[serde(rename = "auth")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_auth: Option<VaultAuthMethod>,
+    /// mount of the approle or cert auth method, defaults to the method name
+    #[arg(long = "vault-auth-mount")]
+    #[serde(rename = "auth_mount")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_auth_mount: Option<String>,
+    /// token for token auth
+    #[arg(long = "vault-token")]
+    #[serde(rename = "token")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "secret::serialize_exposed")]
+    pub vault_token: Option<Secret>,
+    /// approle role id
+    #[arg(long = "vault-role-id")]
+    #[serde(rename = "role_id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_role_id: Option<String>,
+    /// approle secret id
+    #[arg(long = "vault-secret-id")]
+    #[serde(rename = "secret_id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "secret::serialize_exposed")]
+    pub vault_secret_id: Option<Secret>,
+    /// cert auth role, every role is tried if not set
+    #[arg(long = "vault-cert-role")]
+    #[serde(rename = "cert_role")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_cert_role: Option<String>,
+    /// kv v2 mount holding the keys
+    #[arg(long = "vault-kv-mount")]
+    #[serde(rename = "kv_mount")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_kv_mount: Option<String>,
+    /// kv v2 secret path holding the keys
+    #[arg(long = "vault-kv-path")]
+    #[serde(rename = "kv_path")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_kv_path: Option<String>,
+    /// fields of the kv secret to use, all fields sorted by name if not set
+    #[arg(long = "vault-kv-fields", use_value_delimiter = true)]
+    #[serde(rename = "kv_fields")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_kv_fields: Option<Vec<String>>,
+    /// transit mount used to decrypt the ciphertexts
+    #[arg(long = "vault-transit-mount")]
+    #[serde(rename = "transit_mount")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_transit_mount: Option<String>,
+    /// transit key the ciphertexts are encrypted with
+    #[arg(long = "vault-transit-key")]
+    #[serde(rename = "transit_key")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_transit_key: Option<String>,
+    /// transit ciphertexts of the unseal keys
+    #[arg(long = "vault-ciphertexts", use_value_delimiter = true)]
+    #[serde(rename = "ciphertexts")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vault_ciphertexts: Option<Vec<String>>,
+    /// tls settings for the key vault, config file only
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<Tls>,
+}
+
+#[derive(Debug, Clone)]
+pub enum VaultAuth {
+    Token(Secret),
+    AppRole {
+        mount: String,
+        role_id: String,
+        secret_id: Secret,
+    },
+    Cert {
+        mount: String,
+        role: Option<String>,
+    },
+}
+
+/// Where the key vault keeps the unseal keys
+#[derive(Debug, Clone)]
+pub enum VaultKeys {
+    Kv {
+        mount: String,
+        path: String,
+        fields: Vec<String>,
+    },
+    Transit {
+        mount: String,
+        key: String,
+        ciphertexts: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct VaultSource {
+    pub address: Url,
+    pub tls: Tls,
+    pub auth: VaultAuth,
+    pub keys: VaultKeys,
+}
+
+// Key sources must not fetch unseal keys over plaintext http unless allowed
+fn require_https(url: &Url, allow_insecure_http: bool) -> Result<()> {
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if allow_insecure_http => Ok(()),
+        _ => {
+            let report = Report::new(Error::InsecureSourceUrl).attach(format!(
+                "{url} must use https, set allow_insecure_source_http to allow plaintext http"
+            ));
+            Err(report)
+        }
+    }
+}
+
+impl VaultSource {
+    fn from_external(vault: ExternalVaultSource, allow_insecure_http: bool) -> Result<Self> {
+        let invalid = |message: &str| {
+            Report::new(Error::InvalidSourceConfig).attach(format!("vault source {message}"))
+        };
+
+        let Some(address) = vault.vault_addr else {
+            return Err(invalid("address must be specified"));
+        };
+        require_https(&address, allow_insecure_http)?;
+
+        let method = vault.vault_auth.unwrap_or(VaultAuthMethod::Token);
+        let auth = match method {
+            VaultAuthMethod::Token => VaultAuth::Token(
+                vault
+                    .vault_token
+                    .ok_or_else(|| invalid("token must be specified for token auth"))?,
+            ),
+            VaultAuthMethod::Approle => match (vault.vault_role_id, vault.vault_secret_id) {
+                (Some(role_id), Some(secret_id)) => VaultAuth::AppRole {
+                    mount: vault
+                        .vault_auth_mount
+                        .unwrap_or_else(|| "approle".to_owned()),
+                    role_id,
+                    secret_id,
+                },
+                _ => {
+                    return Err(invalid(
+                        "role_id and secret_id must be specified for approle auth",
+                    ));
+                }
+            },
+            VaultAuthMethod::Cert => VaultAuth::Cert {
+                mount: vault.vault_auth_mount.unwrap_or_else(|| "cert".to_owned()),
+                role: vault.vault_cert_role,
+            },
+        };
+
+        let keys = match (vault.vault_kv_path, vault.vault_ciphertexts) {
+            (Some(path), None) => VaultKeys::Kv {
+                mount: vault.vault_kv_mount.unwrap_or_else(|| "secret".to_owned()),
+                path,
+                fields: vault.vault_kv_fields.unwrap_or_default(),
+            },
+            (None, Some(ciphertexts)) if !ciphertexts.is_empty() => VaultKeys::Transit {
+                mount: vault
+                    .vault_transit_mount
+                    .unwrap_or_else(|| "transit".to_owned()),
+                key: vault
+                    .vault_transit_key
+                    .ok_or_else(|| invalid("transit_key must be specified for ciphertexts"))?,
+                ciphertexts,
+            },
+            _ => return Err(invalid("needs exactly one of kv_path or ciphertexts")),
+        };
+
+        let tls = vault.tls.unwrap_or_default();
+        tls.validate(&address)?;
+        if method == VaultAuthMethod::Cert && tls.client_cert.is_none() {
+            return Err(invalid("cert auth requires tls client_cert and client_key"));
+        }
+
+        Ok(VaultSource {
+            address,
+            tls,
+            auth,
+            keys,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyringKind {
+    Session,
+    #[default]
+    User,
+    /// survives logouts and daemon restarts, see persistent-keyring(7)
+    Persistent,
+}
+
+#[derive(Debug, Args, Clone, Deserialize, Serialize)]
+pub struct ExternalKeyring {
+    /// kernel keyring holding the keys default: user
+    #[arg(long = "keyring")]
+    #[serde(rename = "keyring")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyring_kind: Option<KeyringKind>,
+    /// description of the key holding the unseal keys default: vault-unseal
+    #[arg(long = "keyring-description")]
+    #[serde(rename = "description")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyring_description: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keyring {
+    pub keyring: KeyringKind,
+    pub description: String,
+}
+
+impl From<ExternalKeyring> for Keyring {
+    fn from(keyring: ExternalKeyring) -> Self {
+        Keyring {
+            keyring: keyring.keyring_kind.unwrap_or_default(),
+            description: keyring
+                .keyring_description
+                .unwrap_or_else(|| "vault-unseal".to_owned()),
+        }
+    }
+}
+
+#[derive(Debug, Args, Clone, Deserialize, Serialize)]
+pub struct ExternalSystemdCredentials {
+    /// credential names holding the keys default: vault-unseal
+    #[arg(long = "systemd-credentials", use_value_delimiter = true)]
+    #[serde(rename = "credentials")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub systemd_credentials: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SystemdCredentials {
+    pub names: Vec<String>,
+}
+
+impl TryFrom<ExternalSystemdCredentials> for SystemdCredentials {
+    type Error = Report<Error>;
+
+    fn try_from(systemd: ExternalSystemdCredentials) -> std::result::Result<Self, Self::Error> {
+        let names = systemd.systemd_credentials.unwrap_or_default();
+        if names.is_empty() {
+            let report = Report::new(Error::InvalidSourceConfig)
+                .attach("at least one systemd credential must be specified");
+            return Err(report);
+        }
+
+        // credential names are plain file names inside $CREDENTIALS_DIRECTORY
+        if let Some(name) = names
+            .iter()
+            .find(|n| n.is_empty() || n.contains('/') || *n == "." || *n == "..")
+        {
+            let report = Report::new(Error::InvalidSourceConfig)
+                .attach(format!("invalid systemd credential name {name:?}"));
+            return Err(report);
+        }
+
+        Ok(SystemdCredentials { names })
+    }
+}
+
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ExternalKeepass {
+    /// kdbx4 database holding the keys
+    #[arg(long = "keepass-file")]
+    #[serde(rename = "path")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keepass_path: Option<PathBuf>,
+    /// file holding the database password
+    #[arg(long = "keepass-password-file")]
+    #[serde(rename = "password_file")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keepass_password_file: Option<PathBuf>,
+    /// environment variable holding the database password
+    #[arg(long = "keepass-password-env")]
+    #[serde(rename = "password_env")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keepass_password_env: Option<String>,
+    /// key file of the database
+    #[arg(long = "keepass-key-file")]
+    #[serde(rename = "key_file")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keepass_key_file: Option<PathBuf>,
+    /// group holding the key entries, e.g. Infra/Vault, the root if not set
+    #[arg(long = "keepass-group")]
+    #[serde(rename = "group")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keepass_group: Option<String>,
+    /// titles of the key entries, every entry of the group if not set
+    #[arg(long = "keepass-titles", use_value_delimiter = true)]
+    #[serde(rename = "titles")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keepass_titles: Option<Vec<String>>,
+    /// entry field holding the key, standard or custom default: Password
+    #[arg(long = "keepass-field")]
+    #[serde(rename = "field")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keepass_field: Option<String>,
+}
+
+/// Where the KeePass database password is read from on every fetch
+#[derive(Debug, Clone)]
+pub enum KeepassPassword {
+    File(PathBuf),
+    Env(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Keepass {
+    pub path: PathBuf,
+    pub password: Option<KeepassPassword>,
+    pub key_file: Option<PathBuf>,
+    pub group: Vec<String>,
+    pub titles: Vec<String>,
+    pub field: String,
+}
+
+impl TryFrom<ExternalKeepass> for Keepass {
+    type Error = Report<Error>;
+
+    fn try_from(keepass: ExternalKeepass) -> std::result::Result<Self, Self::Error> {
+        let Some(path) = keepass.keepass_path else {
+            let report =
+                Report::new(Error::InvalidSourceConfig).attach("keepass path must be specified");
+            return Err(report);
+        };
+
+        let password = match (keepass.keepass_password_file, keepass.keepass_password_env) {
+            (Some(file), None) => Some(KeepassPassword::File(file)),
+            (None, Some(var)) => Some(KeepassPassword::Env(var)),
+            (None, None) => None,
+            (Some(_), Some(_)) => {
+                let report = Report::new(Error::InvalidSourceConfig)
+                    .attach("only one of keepass password_file or password_env can be specified");
+                return Err(report);
+            }
+        };
+        if password.is_none() && keepass.keepass_key_file.is_none() {
+            let report = Report::new(Error::InvalidSourceConfig)
+                .attach("keepass needs a password, a key file or both");
+            return Err(report);
+        }
+
+        Ok(Keepass {
+            path,
+            password,
+            key_file: keepass.keepass_key_file,
+            group: keepass
+                .keepass_group
+                .unwrap_or_default()
+                .split('/')
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            titles: keepass.keepass_titles.unwrap_or_default(),
+            field: keepass
+                .keepass_field
+                .unwrap_or_else(|| "Password".to_owned()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpMethod {
+    #[default]
+    Get,
+    Post,
+}
+
+#[derive(Debug, Args, Clone, Deserialize, Serialize)]
+pub struct ExternalHttp {
+    /// url answering with the keys as json
+    #[arg(long = "http-url")]
+    #[serde(rename = "url")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_url: Option<Url>,
+    /// request method default: get
+    #[arg(long = "http-method")]
+    #[serde(rename = "method")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_method: Option<HttpMethod>,
+    /// json request body
+    #[arg(long = "http-body")]
+    #[serde(rename = "body")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_body: Option<Secret>,
+    /// bearer token
+    #[arg(long = "http-token")]
+    #[serde(rename = "token")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "secret::serialize_exposed")]
+    pub http_token: Option<Secret>,
+    /// basic auth username
+    #[arg(long = "http-username")]
+    #[serde(rename = "username")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_username: Option<String>,
+    /// basic auth password
+    #[arg(long = "http-password")]
+    #[serde(rename = "password")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(serialize_with = "secret::serialize_exposed")]
+    pub http_password: Option<Secret>,
+    /// json pointers to the keys in the response, e.g. /fields/0/value
+    #[arg(long = "http-pointers", use_value_delimiter = true)]
+    #[serde(rename = "pointers")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_pointers: Option<Vec<String>>,
+    /// request timeout in seconds default: 10
+    #[arg(long = "http-timeout")]
+    #[serde(rename = "timeout")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_timeout: Option<u64>,
+    /// largest accepted response in bytes default: 65536
+    #[arg(long = "http-max-response-bytes")]
+    #[serde(rename = "max_response_bytes")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_max_response_bytes: Option<u64>,
+    /// tls settings, client_cert and client_key enable mtls, config file only
+    #[arg(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<Tls>,
+}
+
+#[derive(Debug, Clone)]
+pub enum HttpAuth {
+    None,
+    Bearer(Secret),
+    Basic { username: String, password: Secret },
+}
+
+#[derive(Debug, Clone)]
+pub struct Http {
+    pub url: Url,
+    pub method: HttpMethod,
+    pub body: Option<Secret>,
+    pub auth: HttpAuth,
+    pub tls: Tls,
+    pub pointers: Vec<String>,
+    pub timeout: u64,
+    pub max_response_bytes: u64,
+}
+
+impl Http {
+    fn from_external(http: ExternalHttp, allow_insecure_http: bool) -> Result<Self> {
+        let invalid = |message: &str| {
+            Report::new(Error::InvalidSourceConfig).attach(format!("http source {message}"))
+        };
+
+        let Some(url) = http.http_url else {
+            return Err(invalid("url must be specified"));
+        };
+        require_https(&url, allow_insecure_http)?;
+
+        let auth = match (http.http_token, http.http_username, http.http_password) {
+            (None, None, None) => HttpAuth::None,
+            (Some(token), None, None) => HttpAuth::Bearer(token),
+            (None, Some(username), Some(password)) => HttpAuth::Basic { username, password },
+            _ => return Err(invalid("needs either a token or a username and password")),
+        };
+
+        let pointers = http.http_pointers.unwrap_or_default();
+        if pointers.is_empty() {
+            return Err(invalid("needs at least one json pointer"));
+        }
+        if let Some(pointer) = pointers.iter().find(|p| !p.starts_with('/')) {
+            return Err(invalid(&format!("pointer {pointer} must start with /")));
+        }
+
+        let tls = http.tls.unwrap_or_default();
+        tls.validate(&url)?;
+
+        Ok(Http {
+            url,
+            method: http.http_method.unwrap_or_default(),
+            body: http.http_body,
+            auth,
+            tls,
+            pointers,
+            timeout: http.http_timeout.unwrap_or(10),
+            max_response_bytes: http.http_max_response_bytes.unwrap_or(64 * 1024),
+        })
+    }
+}
+
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ExternalAws {
+    /// secrets manager secret ids or arns holding the keys as a json array
+    /// or one per line, key/value secrets are rejected
+    #[arg(long = "aws-secret-ids", use_value_delimiter = true)]
+    #[serde(rename = "secret_ids")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aws_secret_ids: Option<Vec<String>>,
+    /// ssm SecureString parameter names holding the keys
+    #[arg(long = "aws-parameters", use_value_delimiter = true)]
+    #[serde(rename = "parameters")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aws_parameters: Option<Vec<String>>,
+    /// aws region, taken from the environment or profile if not set
+    #[arg(long = "aws-region")]
+    #[serde(rename = "region")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aws_region: Option<String>,
+    /// endpoint url override, e.g. http://localhost:4566 for localstack,
+    /// plaintext http needs allow_insecure_source_http
+    #[arg(long = "aws-endpoint-url")]
+    #[serde(rename = "endpoint_url")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aws_endpoint_url: Option<Url>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Aws {
+    pub secret_ids: Vec<String>,
+    pub parameters: Vec<String>,
+    pub region: Option<String>,
+    pub endpoint_url: Option<Url>,
+}
+
+impl Aws {
+    fn from_external(aws: ExternalAws, allow_insecure_http: bool) -> Result<Self> {
+        let secret_ids = aws.aws_secret_ids.unwrap_or_default();
+        let parameters = aws.aws_parameters.unwrap_or_default();
+        if secret_ids.is_empty() && parameters.is_empty() {
+            let report = Report::new(Error::InvalidSourceConfig)
+                .attach("aws needs at least one secret id or parameter");
+            return Err(report);
+        }
+
+        if let Some(endpoint_url) = &aws.aws_endpoint_url {
+            require_https(endpoint_url, allow_insecure_http)?;
+        }
+
+        Ok(Aws {
+            secret_ids,
+            parameters,
+            region: aws.aws_region,
+            endpoint_url: aws.aws_endpoint_url,
+        })
+    }
 }
 
-type Result<T> = std::result::Result<T, Report<Error>>;
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct VaultNode {
-    pub host: Url,
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ExternalKubernetes {
+    /// api server url, derived from KUBERNETES_SERVICE_HOST if not set
+    #[arg(long = "k8s-api-server")]
+    #[serde(rename = "api_server")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_api_server: Option<Url>,
+    /// namespace of the secret, the pod's namespace if not set
+    #[arg(long = "k8s-namespace")]
+    #[serde(rename = "namespace")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_namespace: Option<String>,
+    /// name of the secret holding the keys
+    #[arg(long = "k8s-secret")]
+    #[serde(rename = "name")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_secret: Option<String>,
+    /// data keys of the secret to use, every key sorted by name if not set
+    #[arg(long = "k8s-keys", use_value_delimiter = true)]
+    #[serde(rename = "keys")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_keys: Option<Vec<String>>,
+    /// service account token file
+    #[arg(long = "k8s-token-file")]
+    #[serde(rename = "token_file")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_token_file: Option<PathBuf>,
+    /// ca bundle of the api server
+    #[arg(long = "k8s-ca-file")]
+    #[serde(rename = "ca_file")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_ca_file: Option<PathBuf>,
+    /// request timeout in seconds default: 10
+    #[arg(long = "k8s-timeout")]
+    #[serde(rename = "timeout")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k8s_timeout: Option<u64>,
 }
 
-impl FromStr for VaultNode {
+#[derive(Debug, Clone)]
+pub struct Kubernetes {
+    pub api_server: Url,
+    pub namespace: String,
+    pub name: String,
+    pub keys: Vec<String>,
+    pub token_file: PathBuf,
+    pub tls: Tls,
+    pub timeout: u64,
+}
+
+impl Kubernetes {
+    fn from_external(k8s: ExternalKubernetes, allow_insecure_http: bool) -> Result<Self> {
+        let invalid = |message: String| {
+            Report::new(Error::InvalidSourceConfig).attach(format!("kubernetes source {message}"))
+        };
+        let service_account = PathBuf::from(SERVICE_ACCOUNT_DIR);
+
+        let Some(name) = k8s.k8s_secret else {
+            return Err(invalid("secret name must be specified".to_owned()));
+        };
+
+        let api_server = match k8s.k8s_api_server {
+            Some(url) => url,
+            None => {
+                let host = std::env::var("KUBERNETES_SERVICE_HOST")
+                    .unwrap_or_else(|_| "kubernetes.default.svc".to_owned());
+                let port =
+                    std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_owned());
+                let host = if host.contains(':') {
+                    format!("[{host}]")
+                } else {
+                    host
+                };
+                Url::parse(&format!("https://{host}:{port}"))
+                    .map_err(|e| invalid(format!("has an invalid api server url: {e}")))?
+            }
+        };
+        require_https(&api_server, allow_insecure_http)?;
+
+        let namespace = match k8s.k8s_namespace {
+            Some(namespace) => namespace,
+            None => std::fs::read_to_string(service_account.join("namespace"))
+                .map(|namespace| namespace.trim().to_owned())
+                .map_err(|_| invalid("namespace must be specified outside a pod".to_owned()))?,
+        };
+
+        let ca_file = k8s
+            .k8s_ca_file
+            .unwrap_or_else(|| service_account.join("ca.crt"));
+        let tls = Tls {
+            ca_file: (api_server.scheme() == "https").then_some(ca_file),
+            ..Tls::default()
+        };
+
+        Ok(Kubernetes {
+            api_server,
+            namespace,
+            name,
+            keys: k8s.k8s_keys.unwrap_or_default(),
+            token_file: k8s
+                .k8s_token_file
+                .unwrap_or_else(|| service_account.join("token")),
+            tls,
+            timeout: k8s.k8s_timeout.unwrap_or(10),
+        })
+    }
+}
+
+/// A key source that must contribute shares to a quorum, `<type>[:<min>]`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct QuorumMember {
+    pub kind: SourceType,
+    /// distinct shares the source must provide, 1 if not set
+    pub min: u64,
+}
+
+impl FromStr for QuorumMember {
     type Err = Report<Error>;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        Ok(VaultNode {
-            host: Url::parse(s).change_context(Error::InvalidVaultNodeUrl)?,
+        let invalid = |message: String| {
+            Report::new(Error::InvalidSourceConfig)
+                .attach(format!("expected <source type>[:<min shares>], got {s}"))
+                .attach(message)
+        };
+
+        let (kind, min) = match s.split_once(':') {
+            Some((kind, min)) => (kind.trim(), Some(min.trim())),
+            None => (s.trim(), None),
+        };
+
+        // the config file names, e.g. age_file, the cli spelling age-file
+        // is accepted as well
+        let name = kind.replace('-', "_");
+        let kind = SourceType::value_variants()
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == name)
+            .ok_or_else(|| invalid(format!("unknown source type {kind}")))?;
+        let min = match min {
+            Some(min) => min
+                .parse()
+                .map_err(|_| invalid(format!("invalid number of shares {min}")))?,
+            None => 1,
+        };
+
+        Ok(QuorumMember { kind, min })
+    }
+}
+
+impl TryFrom<String> for QuorumMember {
+    type Error = Report<Error>;
+
+    fn try_from(s: String) -> std::result::Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for QuorumMember {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.kind, self.min)
+    }
+}
+
+impl From<QuorumMember> for String {
+    fn from(member: QuorumMember) -> Self {
+        member.to_string()
+    }
+}
+
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ExternalQuorum {
+    /// key sources that must all contribute, e.g. bitwarden:2,age_file:1
+    #[arg(long = "quorum-sources", use_value_delimiter = true)]
+    #[serde(rename = "sources")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quorum_sources: Option<Vec<QuorumMember>>,
+    /// key shares the vault nodes need to unseal, no single source may be
+    /// required to provide that many
+    #[arg(long = "quorum-threshold")]
+    #[serde(rename = "threshold")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quorum_threshold: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuorumSource {
+    pub source: Source,
+    pub min: u64,
+}
+
+fn quorum_from_external(config: &ExternalConfig) -> Result<Vec<QuorumSource>> {
+    let members = config.quorum.quorum_sources.clone().unwrap_or_default();
+    let invalid = |message: String| {
+        Report::new(Error::InvalidSourceConfig).attach(format!("quorum source {message}"))
+    };
+
+    if members.len() < 2 {
+        return Err(invalid("needs at least two key sources".to_owned()));
+    }
+    let Some(threshold) = config.quorum.quorum_threshold else {
+        return Err(invalid(
+            "needs the unseal threshold of the vault nodes".to_owned(),
+        ));
+    };
+
+    let mut sources = Vec::new();
+    for (i, member) in members.iter().enumerate() {
+        if matches!(member.kind, SourceType::Quorum | SourceType::Fallback) {
+            return Err(invalid(format!("cannot contain a {} source", member.kind)));
+        }
+        if members[..i].iter().any(|m| m.kind == member.kind) {
+            return Err(invalid(format!("lists {member} more than once")));
+        }
+        if member.min == 0 {
+            return Err(invalid(format!("{member} must require at least one share")));
+        }
+        if member.min >= threshold {
+            return Err(invalid(format!(
+                "{member} alone meets the unseal threshold {threshold}"
+            )));
+        }
+
+        sources.push(QuorumSource {
+            source: Source::of_type(member.kind, config)?,
+            min: member.min,
+        });
+    }
+
+    // the required shares alone have to unseal, otherwise the rest could
+    // come from any one source
+    let required: u64 = members.iter().map(|member| member.min).sum();
+    if required < threshold {
+        return Err(invalid(format!(
+            "minimums add up to {required} shares, below the unseal threshold {threshold}"
+        )));
+    }
+
+    Ok(sources)
+}
+
+#[derive(Debug, Args, Clone, Default, Deserialize, Serialize)]
+pub struct ExternalFallback {
+    /// key sources tried in order until one provides keys
+    #[arg(long = "fallback-sources", use_value_delimiter = true)]
+    #[serde(rename = "sources")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_sources: Option<Vec<SourceType>>,
+    /// seconds a failed key source is tried after the others default: 60
+    #[arg(long = "fallback-cooldown")]
+    #[serde(rename = "cooldown")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback_cooldown: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Fallback {
+    pub sources: Vec<Source>,
+    pub cooldown: u64,
+}
+
+impl Fallback {
+    fn from_external(config: &ExternalConfig) -> Result<Self> {
+        let kinds = config.fallback.fallback_sources.clone().unwrap_or_default();
+        let invalid = |message: String| {
+            Report::new(Error::InvalidSourceConfig).attach(format!("fallback source {message}"))
+        };
+
+        if kinds.len() < 2 {
+            return Err(invalid("needs at least two key sources".to_owned()));
+        }
+
+        let mut sources = Vec::new();
+        for (i, kind) in kinds.iter().enumerate() {
+            if *kind == SourceType::Fallback {
+                return Err(invalid("cannot contain another fallback".to_owned()));
+            }
+            if kinds[..i].contains(kind) {
+                return Err(invalid(format!("lists {kind} more than once")));
+            }
+            sources.push(Source::of_type(*kind, config)?);
+        }
+
+        Ok(Fallback {
+            sources,
+            cooldown: config.fallback.fallback_cooldown.unwrap_or(60),
         })
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceType {
+    #[default]
+    Bitwarden,
+    AgeFile,
+    Sops,
+    Exec,
+    Vault,
+    Keyring,
+    Systemd,
+    Keepass,
+    Http,
+    Aws,
+    Kubernetes,
+    Quorum,
+    Fallback,
+}
+
+impl SourceType {
+    /// name of the source type in the config file
+    pub fn name(self) -> &'static str {
+        match self {
+            SourceType::Bitwarden => "bitwarden",
+            SourceType::AgeFile => "age_file",
+            SourceType::Sops => "sops",
+            SourceType::Exec => "exec",
+            SourceType::Vault => "vault",
+            SourceType::Keyring => "keyring",
+            SourceType::Systemd => "systemd",
+            SourceType::Keepass => "keepass",
+            SourceType::Http => "http",
+            SourceType::Aws => "aws",
+            SourceType::Kubernetes => "kubernetes",
+            SourceType::Quorum => "quorum",
+            SourceType::Fallback => "fallback",
+        }
+    }
+}
+
+impl fmt::Display for SourceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[derive(Debug, Args, Clone, Deserialize, Serialize)]
-pub struct ExternalBitwarden {
-    /// bitwarden host
-    #[clap(long = "bw-host")]
-    #[serde(rename = "host")]
+pub struct ExternalSource {
+    /// key source type default: bitwarden
+    #[arg(long = "source-type")]
+    #[serde(rename = "type")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub bw_host: Option<Url>,
-    /// bitwarden token
-    #[clap(long = "bw-token")]
-    #[serde(rename = "token")]
+    pub kind: Option<SourceType>,
+    /// seconds fetched keys are kept in memory for the other workers default: 0
+    #[arg(long = "source-key-ttl")]
+    #[serde(rename = "key_ttl")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub bw_token: Option<String>,
-    /// bitwarden secret ids
-    #[clap(long = "bw-secret-ids", use_value_delimiter = true)]
-    #[serde(rename = "secret_ids")]
+    pub key_ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Source {
+    Bitwarden(Bitwarden),
+    AgeFile(AgeFile),
+    Sops(Sops),
+    Exec(Exec),
+    Vault(VaultSource),
+    Keyring(Keyring),
+    Systemd(SystemdCredentials),
+    Keepass(Keepass),
+    Http(Http),
+    Aws(Aws),
+    Kubernetes(Kubernetes),
+    Quorum(Vec<QuorumSource>),
+    Fallback(Fallback),
+    /// shares typed in by an operator with `vault-unseal manual`
+    Manual(Vec<Secret>),
+}
+
+impl Source {
+    fn from_external(config: &ExternalConfig) -> Result<Self> {
+        Self::of_type(config.source.kind.unwrap_or_default(), config)
+    }
+
+    fn of_type(kind: SourceType, config: &ExternalConfig) -> Result<Self> {
+        let source = match kind {
+            SourceType::Bitwarden => Source::Bitwarden(config.bitwarden.clone().try_into()?),
+            SourceType::AgeFile => Source::AgeFile(config.age_file.clone().try_into()?),
+            SourceType::Sops => Source::Sops(config.sops.clone().try_into()?),
+            SourceType::Exec => Source::Exec(config.exec.clone().try_into()?),
+            SourceType::Vault => Source::Vault(VaultSource::from_external(
+                config.vault.clone(),
+                config.allow_insecure_source_http.unwrap_or(false),
+            )?),
+            SourceType::Keyring => Source::Keyring(config.keyring.clone().into()),
+            SourceType::Systemd => Source::Systemd(config.systemd.clone().try_into()?),
+            SourceType::Keepass => Source::Keepass(config.keepass.clone().try_into()?),
+            SourceType::Http => Source::Http(Http::from_external(
+                config.http.clone(),
+                config.allow_insecure_source_http.unwrap_or(false),
+            )?),
+            SourceType::Aws => Source::Aws(Aws::from_external(
+                config.aws.clone(),
+                config.allow_insecure_source_http.unwrap_or(false),
+            )?),
+            SourceType::Kubernetes => Source::Kubernetes(Kubernetes::from_external(
+                config.kubernetes.clone(),
+                config.allow_insecure_source_http.unwrap_or(false),
+            )?),
+            SourceType::Quorum => Source::Quorum(quorum_from_external(config)?),
+            SourceType::Fallback => Source::Fallback(Fallback::from_external(config)?),
+        };
+
+        Ok(source)
+    }
+}
+
+#[derive(Debug, Args, Clone, Serialize, Deserialize)]
+pub struct ExternalHardening {
+    /// do not lock process memory, unseal keys may be swapped to disk
+    #[arg(long = "disable-mlock")]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub bw_secret_ids: Option<Vec<Uuid>>,
+    pub disable_mlock: Option<bool>,
+    /// refuse to start if process memory cannot be locked
+    #[arg(long = "require-mlock")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub require_mlock: Option<bool>,
+    /// disable core dumps and ptrace access to the process
+    #[arg(long = "disable-core-dumps")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub disable_core_dumps: Option<bool>,
 }
 
-#[derive(Debug, Args, Clone, Deserialize, Serialize)]
-pub struct Bitwarden {
-    pub host: Url,
-    pub token: String,
-    pub secret_ids: Vec<Uuid>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Hardening {
+    pub disable_mlock: bool,
+    pub require_mlock: bool,
+    pub disable_core_dumps: bool,
+}
+
+impl From<ExternalHardening> for Hardening {
+    fn from(hardening: ExternalHardening) -> Self {
+        Hardening {
+            disable_mlock: hardening.disable_mlock.unwrap_or(false),
+            require_mlock: hardening.require_mlock.unwrap_or(false),
+            disable_core_dumps: hardening.disable_core_dumps.unwrap_or(true),
+        }
+    }
 }
 
 #[derive(Debug, Args, Clone, Serialize, Deserialize)]
@@ -104,6 +1722,15 @@ pub struct Log {
     pub json: bool,
 }
 
+impl From<ExternalLog> for Log {
+    fn from(log: ExternalLog) -> Self {
+        Log {
+            level: log.level.unwrap_or(LogLevel::Info),
+            json: log.json.unwrap_or(false),
+        }
+    }
+}
+
 #[derive(Debug, Args, Clone, Deserialize, Serialize)]
 pub struct ExternalConfig {
     /// vault nodes url
@@ -111,11 +1738,61 @@ pub struct ExternalConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub vault_nodes: Option<Vec<VaultNode>>,
     #[command(flatten)]
+    pub source: ExternalSource,
+    #[command(flatten)]
     pub bitwarden: ExternalBitwarden,
+    #[command(flatten)]
+    pub age_file: ExternalAgeFile,
+    #[command(flatten)]
+    pub sops: ExternalSops,
+    #[command(flatten)]
+    pub exec: ExternalExec,
+    #[command(flatten)]
+    pub vault: ExternalVaultSource,
+    #[command(flatten)]
+    pub keyring: ExternalKeyring,
+    #[command(flatten)]
+    pub systemd: ExternalSystemdCredentials,
+    #[command(flatten)]
+    pub keepass: ExternalKeepass,
+    #[command(flatten)]
+    pub http: ExternalHttp,
+    #[command(flatten)]
+    pub aws: ExternalAws,
+    #[command(flatten)]
+    pub kubernetes: ExternalKubernetes,
+    #[command(flatten)]
+    pub quorum: ExternalQuorum,
+    #[command(flatten)]
+    pub fallback: ExternalFallback,
     /// check unseal interval
     #[arg(long = "check-interval")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub check_interval: Option<u64>,
+    /// seconds before partial unseal progress that stopped advancing is reset,
+    /// only done when the key source alone can reach the threshold
+    #[arg(long = "stale-progress-timeout")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stale_progress_timeout: Option<u64>,
+    /// allow sending unseal keys to vault nodes over plaintext http
+    #[arg(long = "allow-insecure-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_insecure_http: Option<bool>,
+    /// allow key sources to fetch unseal keys over plaintext http
+    #[arg(long = "allow-insecure-source-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_insecure_source_http: Option<bool>,
+    /// allow plaintext http for vault nodes on a loopback address, unix
+    /// sockets are not supported as the vault client only speaks tcp
+    #[arg(long = "allow-loopback-http")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_loopback_http: Option<bool>,
+    #[command(flatten)]
+    pub identity: ClusterIdentity,
+    #[command(flatten)]
+    pub tls: Tls,
+    #[command(flatten)]
+    pub hardening: ExternalHardening,
     #[command(flatten)]
     pub log: ExternalLog,
 }
@@ -124,12 +1801,75 @@ impl Default for ExternalConfig {
     fn default() -> Self {
         Self {
             vault_nodes: None,
+            source: ExternalSource {
+                kind: Some(SourceType::Bitwarden),
+                key_ttl: Some(0),
+            },
             bitwarden: ExternalBitwarden {
                 bw_host: Some(Url::parse("https://vault.bitwarden.com").unwrap()),
+                bw_api_url: None,
+                bw_identity_url: None,
                 bw_token: None,
                 bw_secret_ids: None,
+                bw_cache_path: None,
+                bw_cache_max_age: Some(86400),
+                bw_cache_age_identity_file: None,
+                bw_cache_keyring_key: None,
+                bw_cache_credential: None,
+            },
+            age_file: ExternalAgeFile::default(),
+            sops: ExternalSops {
+                sops_timeout: Some(30),
+                ..ExternalSops::default()
+            },
+            exec: ExternalExec {
+                exec_timeout: Some(30),
+                exec_max_output_bytes: Some(64 * 1024),
+                ..ExternalExec::default()
+            },
+            vault: ExternalVaultSource::default(),
+            keyring: ExternalKeyring {
+                keyring_kind: Some(KeyringKind::User),
+                keyring_description: Some("vault-unseal".to_owned()),
+            },
+            systemd: ExternalSystemdCredentials {
+                systemd_credentials: Some(vec!["vault-unseal".to_owned()]),
+            },
+            keepass: ExternalKeepass::default(),
+            http: ExternalHttp {
+                http_url: None,
+                http_method: Some(HttpMethod::Get),
+                http_body: None,
+                http_token: None,
+                http_username: None,
+                http_password: None,
+                http_pointers: None,
+                http_timeout: Some(10),
+                http_max_response_bytes: Some(64 * 1024),
+                tls: None,
+            },
+            aws: ExternalAws::default(),
+            kubernetes: ExternalKubernetes {
+                k8s_timeout: Some(10),
+                ..ExternalKubernetes::default()
+            },
+            quorum: ExternalQuorum::default(),
+            fallback: ExternalFallback {
+                fallback_sources: None,
+                fallback_cooldown: Some(60),
             },
             check_interval: Some(10),
+            stale_progress_timeout: Some(60),
+            allow_insecure_http: Some(false),
+            allow_insecure_source_http: Some(false),
+            allow_loopback_http: Some(false),
+            identity: ClusterIdentity::default(),
+            tls: Tls::default(),
+            hardening: ExternalHardening {
+                disable_mlock: Some(false),
+                require_mlock: Some(false),
+                disable_core_dumps: Some(true),
+            },
             log: ExternalLog {
                 level: Some(LogLevel::Info),
                 json: Some(false),
@@ -190,59 +1930,287 @@ impl ExternalConfig {
 #[derive(Debug, Clone)]
 pub struct InternalConfig {
     pub vault_nodes: Vec<VaultNode>,
-    pub bitwarden: Bitwarden,
+    pub source: Source,
+    pub key_ttl: u64,
     pub check_interval: u64,
+    pub stale_progress_timeout: u64,
+    pub hardening: Hardening,
     pub log: Log,
 }
 
+impl InternalConfig {
+    /// Validate the config of `vault-unseal manual`, the configured key source
+    /// is not used, the shares are entered after validation
+    pub fn manual(config: ExternalConfig) -> Result<Self> {
+        Self::build(config, |_| Ok(Source::Manual(Vec::new())))
+    }
+
+    fn build(
+        config: ExternalConfig,
+        source: impl FnOnce(&ExternalConfig) -> Result<Source>,
+    ) -> Result<Self> {
+        if config.vault_nodes.is_none() || config.vault_nodes.as_ref().unwrap().is_empty() {
+            let report = Report::new(Error::InvalidVaultNodeUrl)
+                .attach("at least one vault node must be specified");
+            return Err(report);
+        }
+
+        let source = source(&config)?;
+
+        let vault_nodes = config
+            .vault_nodes
+            .unwrap_or_default()
+            .into_iter()
+            .map(|node| VaultNode {
+                identity: node.identity.or(&config.identity),
+                tls: node.tls.or(&config.tls),
+                ..node
+            })
+            .collect::<Vec<_>>();
+
+        for node in &vault_nodes {
+            node.validate_scheme(
+                config.allow_insecure_http.unwrap(),
+                config.allow_loopback_http.unwrap(),
+            )?;
+            node.tls.validate(&node.host)?;
+            node.identity.validate(&node.host)?;
+        }
+
+        Ok(Self {
+            vault_nodes,
+            source,
+            key_ttl: config.source.key_ttl.unwrap_or(0),
+            check_interval: config.check_interval.unwrap(),
+            stale_progress_timeout: config.stale_progress_timeout.unwrap(),
+            hardening: config.hardening.into(),
+            log: config.log.into(),
+        })
+    }
+}
+
 impl TryFrom<ExternalConfig> for InternalConfig {
     type Error = Report<Error>;
 
     fn try_from(config: ExternalConfig) -> std::result::Result<Self, Self::Error> {
-        let vault_nodes = config.vault_nodes;
+        Self::build(config, Source::from_external)
+    }
+}
 
-        if vault_nodes.is_none() || vault_nodes.as_ref().unwrap().is_empty() {
-            let report = Report::new(Error::InvalidVaultNodeUrl)
-                .attach("at least one vault node must be specified");
-            return Err(report);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoints(host: &str) -> (String, String) {
+        let (api_url, identity_url) = Bitwarden::endpoints(&Url::parse(host).unwrap()).unwrap();
+        (api_url.to_string(), identity_url.to_string())
+    }
+
+    fn bitwarden(external: ExternalBitwarden) -> Bitwarden {
+        ExternalBitwarden {
+            bw_token: Some(Secret::new("token".to_owned())),
+            bw_secret_ids: Some(vec![Uuid::nil()]),
+            ..external
         }
+        .try_into()
+        .unwrap()
+    }
 
-        let bitwarden = match (
-            config.bitwarden.bw_host,
-            config.bitwarden.bw_token,
-            config.bitwarden.bw_secret_ids,
-        ) {
-            (Some(host), Some(token), Some(secret_ids)) => Bitwarden {
-                host,
-                token,
-                secret_ids,
+    #[test]
+    fn bitwarden_cloud_endpoints() {
+        assert_eq!(
+            endpoints("https://vault.bitwarden.com"),
+            (
+                "https://api.bitwarden.com/".to_owned(),
+                "https://identity.bitwarden.com/".to_owned()
+            )
+        );
+        assert_eq!(
+            endpoints("https://vault.bitwarden.eu/"),
+            (
+                "https://api.bitwarden.eu/".to_owned(),
+                "https://identity.bitwarden.eu/".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn bitwarden_self_hosted_endpoints() {
+        assert_eq!(
+            endpoints("https://bw.example.com"),
+            (
+                "https://bw.example.com/api".to_owned(),
+                "https://bw.example.com/identity".to_owned()
+            )
+        );
+        assert_eq!(
+            endpoints("https://example.com/vaultwarden/"),
+            (
+                "https://example.com/vaultwarden/api".to_owned(),
+                "https://example.com/vaultwarden/identity".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn bitwarden_explicit_urls_override_the_host() {
+        let cfg = bitwarden(ExternalBitwarden {
+            bw_host: Some(Url::parse("https://bw.example.com").unwrap()),
+            bw_api_url: Some(Url::parse("https://api.example.com/bw").unwrap()),
+            bw_identity_url: Some(Url::parse("https://id.example.com/bw").unwrap()),
+            ..ExternalBitwarden::default()
+        });
+        assert_eq!(cfg.api_url.as_str(), "https://api.example.com/bw");
+        assert_eq!(cfg.identity_url.as_str(), "https://id.example.com/bw");
+
+        let cfg = bitwarden(ExternalBitwarden {
+            bw_host: Some(Url::parse("https://bw.example.com").unwrap()),
+            bw_identity_url: Some(Url::parse("https://id.example.com/bw").unwrap()),
+            ..ExternalBitwarden::default()
+        });
+        assert_eq!(cfg.api_url.as_str(), "https://bw.example.com/api");
+        assert_eq!(cfg.identity_url.as_str(), "https://id.example.com/bw");
+    }
+
+    #[test]
+    fn source_urls_need_https_unless_allowed() {
+        let https = Url::parse("https://vault.example.com").unwrap();
+        let http = Url::parse("http://localhost:4566").unwrap();
+        let ftp = Url::parse("ftp://vault.example.com").unwrap();
+
+        assert!(require_https(&https, false).is_ok());
+        assert!(require_https(&http, true).is_ok());
+        for (url, allow_insecure_http) in [(&http, false), (&ftp, true)] {
+            let report = require_https(url, allow_insecure_http).unwrap_err();
+            assert!(matches!(report.current_context(), Error::InsecureSourceUrl));
+        }
+    }
+
+    #[test]
+    fn insecure_vault_nodes_do_not_allow_insecure_sources() {
+        let http = |allow_insecure_http, allow_insecure_source_http| ExternalConfig {
+            http: ExternalHttp {
+                http_url: Some(Url::parse("http://keys.example.com/unseal").unwrap()),
+                http_pointers: Some(vec!["/keys".to_owned()]),
+                ..ExternalConfig::default().http
             },
-            (Some(_), _, Some(secret_ids)) => {
-                if secret_ids.is_empty() {
-                    let report = Report::new(Error::MissingBitwardenConfig)
-                        .attach("bitwarden secret ids cannot be empty");
-                    return Err(report);
-                }
-                let report = Report::new(Error::MissingBitwardenConfig)
-                    .attach("bitwarden token must be specified");
-                return Err(report);
-            }
-            (Some(_), Some(_), None) => {
-                let report = Report::new(Error::MissingBitwardenConfig)
-                    .attach("bitwarden secret ids must be specified");
-                return Err(report);
+            allow_insecure_http: Some(allow_insecure_http),
+            allow_insecure_source_http: Some(allow_insecure_source_http),
+            ..ExternalConfig::default()
+        };
+
+        let report = Source::of_type(SourceType::Http, &http(true, false)).unwrap_err();
+        assert!(matches!(report.current_context(), Error::InsecureSourceUrl));
+        assert!(Source::of_type(SourceType::Http, &http(false, true)).is_ok());
+    }
+
+    #[test]
+    fn aws_endpoint_url_needs_https_unless_allowed() {
+        let aws = ExternalAws {
+            aws_secret_ids: Some(vec!["prod/vault/unseal-keys".to_owned()]),
+            aws_endpoint_url: Some(Url::parse("http://localhost:4566").unwrap()),
+            ..ExternalAws::default()
+        };
+
+        let report = Aws::from_external(aws.clone(), false).unwrap_err();
+        assert!(matches!(report.current_context(), Error::InsecureSourceUrl));
+        assert!(Aws::from_external(aws, true).is_ok());
+    }
+
+    #[test]
+    fn quorum_members_use_the_config_names() {
+        let member: QuorumMember = "age_file:2".parse().unwrap();
+        assert_eq!(
+            member,
+            QuorumMember {
+                kind: SourceType::AgeFile,
+                min: 2
             }
-            _ => unreachable!(),
+        );
+        assert_eq!(member.to_string(), "age_file:2");
+        assert_eq!(" age-file : 2".parse::<QuorumMember>().unwrap(), member);
+        assert_eq!("sops".parse::<QuorumMember>().unwrap().min, 1);
+
+        let members: Vec<QuorumMember> =
+            serde_json::from_str(r#"["bitwarden:2", "age_file:1"]"#).unwrap();
+        assert_eq!(members[0].kind, SourceType::Bitwarden);
+        assert_eq!(
+            serde_json::to_string(&members).unwrap(),
+            r#"["bitwarden:2","age_file:1"]"#
+        );
+
+        for invalid in ["agefile:1", "age_file:", "age_file:-1", ":1", ""] {
+            assert!(invalid.parse::<QuorumMember>().is_err(), "{invalid}");
+        }
+    }
+
+    fn quorum(sources: &[&str], threshold: Option<u64>) -> Result<Vec<QuorumSource>> {
+        let config = ExternalConfig {
+            quorum: ExternalQuorum {
+                quorum_sources: Some(sources.iter().map(|s| s.parse().unwrap()).collect()),
+                quorum_threshold: threshold,
+            },
+            sops: ExternalSops {
+                sops_path: Some(PathBuf::from("/etc/vault-unseal/keys.enc.yaml")),
+                sops_key_path: Some("/vault/unseal_keys".to_owned()),
+                ..ExternalConfig::default().sops
+            },
+            exec: ExternalExec {
+                exec_command: Some(vec!["/usr/local/bin/unseal-keys".to_owned()]),
+                ..ExternalConfig::default().exec
+            },
+            ..ExternalConfig::default()
         };
+        quorum_from_external(&config)
+    }
 
-        Ok(Self {
-            vault_nodes: vault_nodes.unwrap_or_default(),
-            bitwarden,
-            check_interval: config.check_interval.unwrap(),
-            log: Log {
-                level: config.log.level.unwrap(),
-                json: config.log.json.unwrap(),
+    #[test]
+    fn quorum_minimums_are_checked_against_the_threshold() {
+        assert!(quorum(&["sops:2", "exec:1"], Some(3)).is_ok());
+
+        for (sources, threshold) in [
+            // no threshold to check against
+            (&["sops:2", "exec:1"], None),
+            // sops alone meets the threshold
+            (&["sops:3", "exec:1"], Some(3)),
+            // the last share could come from either source
+            (&["sops:1", "exec:1"], Some(3)),
+        ] {
+            let report = quorum(sources, threshold).unwrap_err();
+            assert!(matches!(
+                report.current_context(),
+                Error::InvalidSourceConfig
+            ));
+        }
+    }
+
+    #[test]
+    fn cluster_pins_need_a_pin_checked_while_sealed() {
+        let host = Url::parse("https://vault.example.com").unwrap();
+        let cluster = ClusterIdentity {
+            cluster_id: Some("8d5e2f1c".to_owned()),
+            ..ClusterIdentity::default()
+        };
+
+        let report = cluster.validate(&host).unwrap_err();
+        assert!(matches!(
+            report.current_context(),
+            Error::InvalidIdentityPins
+        ));
+
+        let pinned = [
+            ClusterIdentity {
+                seal_type: Some("shamir".to_owned()),
+                ..cluster.clone()
             },
-        })
+            ClusterIdentity {
+                version: Some(">=1.15".parse().unwrap()),
+                ..cluster
+            },
+            ClusterIdentity::default(),
+        ];
+        for identity in pinned {
+            assert!(identity.validate(&host).is_ok());
+        }
     }
 }
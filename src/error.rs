@@ -7,12 +7,15 @@ pub enum Error {
     #[error("configuration error")]
     ConfigError,
 
-    #[error("bitwarden error")]
-    BitwardenError,
+    #[error("key source error")]
+    SourceError,
 
     #[error("worker error")]
     WorkerError,
 
+    #[error("hardening error")]
+    HardeningError,
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 }
@@ -0,0 +1,98 @@
+use error_stack::{Report, ResultExt};
+use thiserror::Error;
+use tracing::{Level, event};
+
+use crate::conf::Hardening;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to lock process memory")]
+    MlockError,
+    #[error("failed to disable core dumps")]
+    CoreDumpError,
+}
+
+pub type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Keep unseal keys out of swap and core files
+pub fn apply(cfg: &Hardening) -> Result<()> {
+    if cfg.disable_core_dumps {
+        disable_core_dumps()?;
+        event!(Level::DEBUG, "core dumps disabled");
+    }
+
+    if cfg.disable_mlock {
+        event!(
+            Level::WARN,
+            "mlock is disabled, unseal keys may be written to swap"
+        );
+        return Ok(());
+    }
+
+    match lock_memory() {
+        Ok(()) => {
+            event!(Level::DEBUG, "process memory locked");
+            Ok(())
+        }
+        Err(report) if cfg.require_mlock => {
+            Err(report
+                .attach("grant CAP_IPC_LOCK, raise RLIMIT_MEMLOCK or set disable_mlock = true"))
+        }
+        Err(report) => {
+            event!(
+                Level::WARN,
+                "unseal keys may be written to swap: {report:?}"
+            );
+            Ok(())
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn lock_memory() -> Result<()> {
+    // SAFETY: mlockall only changes paging behaviour of this process
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        return Err(std::io::Error::last_os_error()).change_context(Error::MlockError);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lock_memory() -> Result<()> {
+    Err(Report::new(Error::MlockError).attach("mlock is only supported on linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn disable_core_dumps() -> Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: limit is a valid rlimit that outlives the call
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .change_context(Error::CoreDumpError)
+            .attach("setrlimit(RLIMIT_CORE) failed");
+    }
+
+    // also blocks ptrace attach and /proc/<pid>/mem from other users
+    // SAFETY: PR_SET_DUMPABLE takes a single integer argument
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .change_context(Error::CoreDumpError)
+            .attach("prctl(PR_SET_DUMPABLE) failed");
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn disable_core_dumps() -> Result<()> {
+    event!(
+        Level::WARN,
+        "disabling core dumps is only supported on linux"
+    );
+    Ok(())
+}
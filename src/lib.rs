@@ -1,25 +1,33 @@
-mod bitwarden;
 mod conf;
 mod error;
+mod harden;
+mod seal;
+mod secret;
 mod shoutdown;
+mod source;
+mod tls;
 mod worker;
 
+#[cfg(test)]
+mod testing;
+
 pub mod cli;
 
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use error_stack::ResultExt;
+use error_stack::{Report, ResultExt};
 use futures::future;
 use tracing::{Level, event, level_filters::LevelFilter};
 use tracing_subscriber::{filter, prelude::*};
 
 use crate::{
-    bitwarden::BitwardenSecret,
     cli::Cli,
-    conf::{ExternalConfig, InternalConfig},
+    conf::{ExternalConfig, InternalConfig, Source},
     error::{Error, Result},
     shoutdown::Shutdown,
+    source::shared::SharedFetcher,
     worker::UnsealWorker,
 };
 
@@ -32,19 +40,20 @@ pub async fn unseal(cfg: InternalConfig) -> Result<()> {
         cfg
     );
 
-    let bitwarden_client = Arc::new(
-        BitwardenSecret::new(&cfg.bitwarden.token, cfg.bitwarden.secret_ids.clone())
-            .await
-            .change_context(Error::BitwardenError)?,
-    );
+    let source = source::from_config(&cfg.source)
+        .await
+        .change_context(Error::SourceError)?;
+    // one fetch per tick for the whole cluster instead of one per node
+    let source = Arc::new(SharedFetcher::new(source, Duration::from_secs(cfg.key_ttl)));
     let shutdown = Arc::new(Shutdown::new());
 
     let mut handles = Vec::new();
     for node in cfg.vault_nodes {
         let worker = UnsealWorker::new(
-            &node.host,
+            &node,
             cfg.check_interval,
-            bitwarden_client.clone(),
+            cfg.stale_progress_timeout,
+            source.clone(),
             shutdown.clone(),
         )
         .change_context(Error::WorkerError)?;
@@ -61,13 +70,17 @@ pub async fn unseal(cfg: InternalConfig) -> Result<()> {
 }
 
 pub fn init_log(cfg: InternalConfig) -> Result<()> {
-    let level: Level = cfg.log.level.into();
+    setup_log(cfg.log)
+}
+
+fn setup_log(log: conf::Log) -> Result<()> {
+    let level: Level = log.level.into();
     let fmt = tracing_subscriber::fmt::format()
         .with_line_number(true)
         .with_ansi(true)
         .with_target(true);
 
-    let fmt_layer = if cfg.log.json {
+    let fmt_layer = if log.json {
         tracing_subscriber::fmt::layer()
             .event_format(fmt)
             .json()
@@ -90,7 +103,94 @@ pub fn init_log(cfg: InternalConfig) -> Result<()> {
     Ok(())
 }
 
+pub fn init_hardening(cfg: InternalConfig) -> Result<()> {
+    harden::apply(&cfg.hardening).change_context(Error::HardeningError)?;
+    Ok(())
+}
+
 pub fn init_cfg(cli: Cli) -> Result<InternalConfig> {
+    let cfg: InternalConfig = external_cfg(&cli)?
+        .try_into()
+        .change_context(Error::ConfigError)?;
+    Ok(cfg)
+}
+
+/// Store unseal keys read from stdin in the configured kernel keyring
+pub fn keyring_load(cli: Cli) -> Result<()> {
+    let cfg = external_cfg(&cli)?;
+    // logging first, hardening warns when memory cannot be locked
+    setup_log(cfg.log.into())?;
+    harden::apply(&cfg.hardening.clone().into()).change_context(Error::HardeningError)?;
+    let keyring: conf::Keyring = cfg.keyring.into();
+
+    let keys = source::manual::read_keys().change_context(Error::SourceError)?;
+    source::keyring::store(&keyring, &keys).change_context(Error::SourceError)?;
+    println!(
+        "stored {} unseal keys in the {:?} keyring as {}",
+        keys.len(),
+        keyring.keyring,
+        keyring.description
+    );
+    Ok(())
+}
+
+/// Unseal every node once with key shares entered by an operator, for when
+/// the key source is unavailable
+pub async fn manual(cli: Cli, reset: bool) -> Result<()> {
+    let mut cfg = InternalConfig::manual(external_cfg(&cli)?).change_context(Error::ConfigError)?;
+    init_log(cfg.clone())?;
+    init_hardening(cfg.clone())?;
+
+    cfg.source = Source::Manual(source::manual::read_keys().change_context(Error::SourceError)?);
+    let source = source::from_config(&cfg.source)
+        .await
+        .change_context(Error::SourceError)?;
+    let shutdown = Arc::new(Shutdown::new());
+
+    let mut attempts = Vec::new();
+    for node in &cfg.vault_nodes {
+        let worker = UnsealWorker::new(
+            node,
+            cfg.check_interval,
+            cfg.stale_progress_timeout,
+            source.clone(),
+            shutdown.clone(),
+        )
+        .change_context(Error::WorkerError)?
+        .with_partial_progress();
+
+        attempts.push(async move { worker.unseal_once(reset).await });
+    }
+
+    let results = future::join_all(attempts).await;
+
+    let mut failed = 0;
+    for (node, result) in cfg.vault_nodes.iter().zip(results) {
+        match result {
+            Ok(status) if !status.sealed => println!("{}: unsealed", node.host),
+            Ok(status) => println!(
+                "{}: sealed, progress {}/{}",
+                node.host, status.progress, status.threshold
+            ),
+            Err(report) => {
+                failed += 1;
+                println!("{}: failed", node.host);
+                eprintln!("{report:?}");
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(Report::new(Error::WorkerError).attach(format!(
+            "{failed} of {} vault nodes failed",
+            cfg.vault_nodes.len()
+        )));
+    }
+    Ok(())
+}
+
+// Merge the config files and cli flags without validating them
+fn external_cfg(cli: &Cli) -> Result<ExternalConfig> {
     let conf_paths: Vec<PathBuf> = {
         let mut paths = Vec::new();
         if let Some(dir) = &cli.conf_dir {
@@ -107,14 +207,10 @@ pub fn init_cfg(cli: Cli) -> Result<InternalConfig> {
                     }),
             );
         } else {
-            paths.push(cli.conf_path);
+            paths.push(cli.conf_path.clone());
         }
         paths
     };
 
-    let cfg: InternalConfig = ExternalConfig::figment(&conf_paths, Some(&cli.config))
-        .change_context(Error::ConfigError)?
-        .try_into()
-        .change_context(Error::ConfigError)?;
-    Ok(cfg)
+    ExternalConfig::figment(&conf_paths, Some(&cli.config)).change_context(Error::ConfigError)
 }
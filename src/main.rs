@@ -6,7 +6,10 @@ use error_stack::{
     fmt::{Charset, ColorMode},
 };
 use rustls::crypto::aws_lc_rs;
-use vault_unseal::{cli::Cli, init_cfg, init_log};
+use vault_unseal::{
+    cli::{Cli, Command, KeyringCommand},
+    init_cfg, init_hardening, init_log,
+};
 
 #[tokio::main]
 async fn main() {
@@ -33,6 +36,24 @@ async fn main() {
     Report::set_charset(charset);
 
     let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Keyring(KeyringCommand::Load)) => {
+            if let Err(e) = vault_unseal::keyring_load(cli) {
+                eprintln!("{e:?}");
+                exit(1);
+            }
+            return;
+        }
+        Some(Command::Manual { reset }) => {
+            if let Err(e) = vault_unseal::manual(cli, reset).await {
+                eprintln!("{e:?}");
+                exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
+
     let cfg = match init_cfg(cli) {
         Ok(cfg) => cfg,
         Err(e) => {
@@ -46,6 +67,11 @@ async fn main() {
         exit(1);
     }
 
+    if let Err(e) = init_hardening(cfg.clone()) {
+        eprintln!("{e:?}");
+        exit(1);
+    }
+
     if let Err(e) = vault_unseal::unseal(cfg).await {
         eprintln!("{e:?}");
         exit(1);
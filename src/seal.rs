@@ -0,0 +1,65 @@
+use rustify_derive::Endpoint;
+use serde::Deserialize;
+use vaultrs::{api, client::Client, error::ClientError};
+use zeroize::Zeroizing;
+
+/// Response from `sys/seal-status`
+#[derive(Debug, Deserialize)]
+pub struct SealStatusResponse {
+    #[serde(rename = "type")]
+    pub seal_type: String,
+    pub initialized: bool,
+    pub sealed: bool,
+    #[serde(rename = "t")]
+    pub threshold: u64,
+    #[serde(rename = "n")]
+    pub shares: u64,
+    pub progress: u64,
+    /// identifies the current unseal attempt, empty while progress is 0
+    #[serde(default)]
+    pub nonce: String,
+    pub version: String,
+    /// only reported once the node has been unsealed
+    #[serde(default)]
+    pub cluster_name: Option<String>,
+    /// only reported once the node has been unsealed
+    #[serde(default)]
+    pub cluster_id: Option<String>,
+}
+
+#[derive(Debug, Default, Endpoint)]
+#[endpoint(path = "sys/seal-status", response = "SealStatusResponse")]
+pub struct SealStatusRequest {}
+
+// `sys/unseal` answers with the same body as `sys/seal-status`, which
+// vaultrs' own unseal response drops the nonce from.
+#[derive(Debug, Default, Endpoint)]
+#[endpoint(path = "sys/unseal", method = "POST", response = "SealStatusResponse")]
+pub struct UnsealRequest {
+    pub key: Option<Zeroizing<String>>,
+    pub reset: Option<bool>,
+}
+
+/// Read the seal status of a vault node, this endpoint is unauthenticated
+/// and answers while the node is still sealed.
+pub async fn status(client: &impl Client) -> Result<SealStatusResponse, ClientError> {
+    api::exec_with_no_result(client, SealStatusRequest {}).await
+}
+
+/// Submit a single unseal key share
+pub async fn unseal(client: &impl Client, key: &str) -> Result<SealStatusResponse, ClientError> {
+    let endpoint = UnsealRequest {
+        key: Some(Zeroizing::new(key.to_owned())),
+        reset: None,
+    };
+    api::exec_with_no_result(client, endpoint).await
+}
+
+/// Discard all key shares submitted for the current unseal attempt
+pub async fn reset(client: &impl Client) -> Result<SealStatusResponse, ClientError> {
+    let endpoint = UnsealRequest {
+        key: None,
+        reset: Some(true),
+    };
+    api::exec_with_no_result(client, endpoint).await
+}
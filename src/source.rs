@@ -0,0 +1,153 @@
+pub mod age_file;
+pub mod aws;
+pub mod bitwarden;
+pub mod cache;
+pub mod exec;
+pub mod fallback;
+pub mod http;
+pub mod keepass;
+pub mod keyring;
+pub mod kubernetes;
+pub mod manual;
+pub mod process;
+pub mod quorum;
+pub mod shared;
+pub mod sops;
+pub mod systemd;
+pub mod vault;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use serde_json::Value;
+use thiserror::Error;
+use zeroize::Zeroize;
+
+use crate::{
+    conf::Source,
+    secret::Secret,
+    source::{
+        age_file::AgeFile, aws::AwsSecrets, bitwarden::BitwardenSecret, exec::ExecCommand,
+        fallback::Fallback, http::HttpJson, keepass::KeepassDatabase, keyring::KernelKeyring,
+        kubernetes::KubernetesSecretSource, manual::ManualKeys, quorum::Quorum, sops::SopsFile,
+        systemd::SystemdCredentials, vault::VaultSecret,
+    },
+};
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("key source create error")]
+    CreateError,
+    #[error("key source fetch error")]
+    FetchError,
+}
+
+pub type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// A provider of vault unseal keys
+#[async_trait]
+pub trait KeySource: Send + Sync {
+    /// name of the provider, used in logs and errors
+    fn name(&self) -> &str;
+
+    /// fetch all unseal keys currently held by the provider
+    async fn fetch_keys(&self) -> Result<Vec<Secret>>;
+
+    /// fetch the keys along with the name of the provider that supplied
+    /// them, sources picking between other sources report the one used
+    async fn fetch_keys_from(&self) -> Result<(Vec<Secret>, String)> {
+        Ok((self.fetch_keys().await?, self.name().to_owned()))
+    }
+}
+
+/// Create the key source selected by `source.type`
+pub async fn from_config(source: &Source) -> Result<Arc<dyn KeySource>> {
+    let source: Arc<dyn KeySource> = match source {
+        Source::Bitwarden(cfg) => Arc::new(
+            BitwardenSecret::new(cfg)
+                .await
+                .change_context(Error::CreateError)?,
+        ),
+        Source::AgeFile(cfg) => Arc::new(AgeFile::new(cfg)),
+        Source::Sops(cfg) => Arc::new(SopsFile::new(cfg)),
+        Source::Exec(cfg) => Arc::new(ExecCommand::new(cfg)),
+        Source::Vault(cfg) => Arc::new(VaultSecret::new(cfg).change_context(Error::CreateError)?),
+        Source::Keyring(cfg) => Arc::new(KernelKeyring::new(cfg)),
+        Source::Systemd(cfg) => Arc::new(SystemdCredentials::new(cfg)),
+        Source::Keepass(cfg) => Arc::new(KeepassDatabase::new(cfg)),
+        Source::Http(cfg) => Arc::new(HttpJson::new(cfg).change_context(Error::CreateError)?),
+        Source::Aws(cfg) => Arc::new(AwsSecrets::new(cfg).await),
+        Source::Kubernetes(cfg) => {
+            Arc::new(KubernetesSecretSource::new(cfg).change_context(Error::CreateError)?)
+        }
+        Source::Quorum(cfg) => Arc::new(Quorum::new(cfg).await?),
+        Source::Fallback(cfg) => Arc::new(Fallback::new(cfg).await?),
+        Source::Manual(keys) => Arc::new(ManualKeys::new(keys)),
+    };
+
+    Ok(source)
+}
+
+/// Parse unseal keys from a json array of strings or from one key per line,
+/// blank lines and lines starting with `#` are skipped
+pub fn parse_keys(text: &str) -> Result<Vec<Secret>> {
+    let text = text.trim();
+    if text.starts_with('[') {
+        return serde_json::from_str(text)
+            .change_context(Error::FetchError)
+            .attach("keys must be a json array of strings");
+    }
+
+    let keys = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| Secret::new(line.to_owned()))
+        .collect();
+    Ok(keys)
+}
+
+/// A json document holding unseal keys, every string is zeroized on drop
+pub struct JsonDocument(pub Value);
+
+impl JsonDocument {
+    /// Take the keys at a json pointer, either a list of keys or a string
+    /// parsed with [`parse_keys`]
+    pub fn take_keys(&mut self, pointer: &str) -> Result<Vec<Secret>> {
+        match self.0.pointer_mut(pointer).map(Value::take) {
+            Some(Value::Array(items)) => items
+                .into_iter()
+                .map(|item| match item {
+                    Value::String(key) => Ok(Secret::new(key)),
+                    _ => Err(Report::new(Error::FetchError)
+                        .attach(format!("{pointer} must only hold strings"))),
+                })
+                .collect(),
+            Some(Value::String(mut keys)) => {
+                let parsed = parse_keys(&keys);
+                keys.zeroize();
+                parsed
+            }
+            Some(_) => Err(Report::new(Error::FetchError)
+                .attach(format!("{pointer} must be a list of keys or a string"))),
+            None => Err(Report::new(Error::FetchError).attach(format!("{pointer} not found"))),
+        }
+    }
+}
+
+impl Drop for JsonDocument {
+    fn drop(&mut self) {
+        scrub(&mut self.0);
+    }
+}
+
+fn scrub(value: &mut Value) {
+    match value {
+        Value::String(s) => s.zeroize(),
+        Value::Array(items) => items.iter_mut().for_each(scrub),
+        Value::Object(map) => map.values_mut().for_each(scrub),
+        _ => {}
+    }
+}
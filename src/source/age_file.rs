@@ -0,0 +1,128 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use age::{Decryptor, IdentityFile, armor::ArmoredReader, scrypt, secrecy::SecretString};
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    conf::{self, AgeIdentity},
+    secret::Secret,
+    source::{self, KeySource},
+};
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("age identity error")]
+    IdentityError,
+    #[error("age decrypt error")]
+    DecryptError,
+}
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Unseal keys stored in a local age encrypted file, readable without any
+/// network access
+pub struct AgeFile {
+    path: PathBuf,
+    identity: AgeIdentity,
+}
+
+impl AgeFile {
+    pub fn new(cfg: &conf::AgeFile) -> Self {
+        Self {
+            path: cfg.path.clone(),
+            identity: cfg.identity.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl KeySource for AgeFile {
+    fn name(&self) -> &str {
+        "age_file"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        let path = self.path.clone();
+        let identity = self.identity.clone();
+
+        // age only offers a blocking reader for files
+        let plaintext = tokio::task::spawn_blocking(move || decrypt(&path, &identity))
+            .await
+            .change_context(source::Error::FetchError)?
+            .change_context(source::Error::FetchError)?;
+
+        source::parse_keys(&plaintext)
+    }
+}
+
+/// Load the configured identities, done on every fetch so rotated files and
+/// passphrases are picked up without a restart
+pub fn identities(identity: &AgeIdentity) -> Result<Vec<Box<dyn age::Identity>>> {
+    let passphrase = match identity {
+        AgeIdentity::IdentityFile(path) => {
+            return IdentityFile::from_file(path.to_string_lossy().into_owned())
+                .change_context(Error::IdentityError)
+                .attach(format!(
+                    "failed to read age identity file {}",
+                    path.display()
+                ))?
+                .into_identities()
+                .change_context(Error::IdentityError)
+                .attach(format!("invalid age identity file {}", path.display()));
+        }
+        AgeIdentity::PassphraseFile(path) => Zeroizing::new(
+            std::fs::read_to_string(path)
+                .change_context(Error::IdentityError)
+                .attach(format!(
+                    "failed to read age passphrase file {}",
+                    path.display()
+                ))?,
+        ),
+        AgeIdentity::PassphraseEnv(var) => Zeroizing::new(
+            std::env::var(var)
+                .change_context(Error::IdentityError)
+                .attach(format!("failed to read age passphrase from ${var}"))?,
+        ),
+    };
+
+    let passphrase = passphrase.trim_end_matches(['\r', '\n']);
+    if passphrase.is_empty() {
+        return Err(Report::new(Error::IdentityError).attach("age passphrase is empty"));
+    }
+
+    let identity = scrypt::Identity::new(SecretString::from(passphrase.to_owned()));
+    Ok(vec![Box::new(identity)])
+}
+
+fn decrypt(path: &Path, identity: &AgeIdentity) -> Result<Zeroizing<String>> {
+    let identities = identities(identity)?;
+
+    let file = File::open(path)
+        .change_context(Error::DecryptError)
+        .attach(format!("failed to open {}", path.display()))?;
+    // accepts both the binary and the ascii armored format
+    let decryptor = Decryptor::new(ArmoredReader::new(BufReader::new(file)))
+        .change_context(Error::DecryptError)
+        .attach(format!("{} is not an age file", path.display()))?;
+
+    let mut reader = decryptor
+        .decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        .change_context(Error::DecryptError)
+        .attach(format!("failed to decrypt {}", path.display()))?;
+
+    let mut plaintext = Zeroizing::new(String::new());
+    reader
+        .read_to_string(&mut plaintext)
+        .change_context(Error::DecryptError)
+        .attach(format!("failed to read {}", path.display()))?;
+
+    Ok(plaintext)
+}
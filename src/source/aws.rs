@@ -0,0 +1,137 @@
+use async_trait::async_trait;
+use aws_config::{BehaviorVersion, Region};
+use error_stack::{Report, ResultExt};
+use thiserror::Error;
+
+use crate::{
+    conf,
+    secret::Secret,
+    source::{self, KeySource},
+};
+
+#[derive(Error, Debug)]
+#[error("aws key source error")]
+pub struct Error;
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Unseal keys kept in AWS Secrets Manager secrets or SecureString SSM
+/// parameters, each holding one or more keys
+pub struct AwsSecrets {
+    secrets_manager: aws_sdk_secretsmanager::Client,
+    ssm: aws_sdk_ssm::Client,
+    secret_ids: Vec<String>,
+    parameters: Vec<String>,
+}
+
+impl AwsSecrets {
+    pub async fn new(cfg: &conf::Aws) -> Self {
+        // env, profile, web identity, ecs and imds, in the usual order
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = &cfg.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        if let Some(endpoint_url) = &cfg.endpoint_url {
+            loader = loader.endpoint_url(endpoint_url.as_str());
+        }
+        let sdk_config = loader.load().await;
+
+        Self {
+            secrets_manager: aws_sdk_secretsmanager::Client::new(&sdk_config),
+            ssm: aws_sdk_ssm::Client::new(&sdk_config),
+            secret_ids: cfg.secret_ids.clone(),
+            parameters: cfg.parameters.clone(),
+        }
+    }
+
+    async fn read_keys(&self) -> Result<Vec<Secret>> {
+        let mut keys = Vec::new();
+
+        for secret_id in &self.secret_ids {
+            let output = self
+                .secrets_manager
+                .get_secret_value()
+                .secret_id(secret_id)
+                .send()
+                .await
+                .change_context(Error)
+                .attach(format!("failed to get secret {secret_id}"))?;
+            let value = Secret::new(output.secret_string.unwrap_or_default());
+            keys.extend(parse(secret_id, &value)?);
+        }
+
+        for name in &self.parameters {
+            let output = self
+                .ssm
+                .get_parameter()
+                .name(name)
+                .with_decryption(true)
+                .send()
+                .await
+                .change_context(Error)
+                .attach(format!("failed to get parameter {name}"))?;
+            let value = Secret::new(
+                output
+                    .parameter
+                    .and_then(|parameter| parameter.value)
+                    .unwrap_or_default(),
+            );
+            keys.extend(parse(name, &value)?);
+        }
+
+        Ok(keys)
+    }
+}
+
+fn parse(name: &str, value: &Secret) -> Result<Vec<Secret>> {
+    if value.expose().is_empty() {
+        return Err(Report::new(Error).attach(format!("{name} has no string value")));
+    }
+    // the console stores key/value secrets as a json object, which would
+    // otherwise end up as a single key
+    if value.expose().trim_start().starts_with('{') {
+        return Err(Report::new(Error).attach(format!(
+            "{name} is a json object, store the keys as a json array or one per line"
+        )));
+    }
+
+    source::parse_keys(value.expose())
+        .change_context(Error)
+        .attach(format!("invalid keys in {name}"))
+}
+
+#[async_trait]
+impl KeySource for AwsSecrets {
+    fn name(&self) -> &str {
+        "aws"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        self.read_keys()
+            .await
+            .change_context(source::Error::FetchError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(value: &str) -> Result<Vec<String>> {
+        let keys = parse("prod/vault/unseal-keys", &Secret::new(value.to_owned()))?;
+        Ok(keys.iter().map(|key| key.expose().to_owned()).collect())
+    }
+
+    #[test]
+    fn arrays_and_lines_are_keys() {
+        assert_eq!(keys(r#"["key-1", "key-2"]"#).unwrap(), ["key-1", "key-2"]);
+        assert_eq!(keys("key-1\nkey-2\n").unwrap(), ["key-1", "key-2"]);
+    }
+
+    #[test]
+    fn objects_and_empty_values_are_rejected() {
+        assert!(keys(r#"{"key-1": "a", "key-2": "b"}"#).is_err());
+        assert!(keys(" \n{\"keys\": []}").is_err());
+        assert!(keys("").is_err());
+    }
+}
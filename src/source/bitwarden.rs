@@ -0,0 +1,415 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bitwarden::{
+    Client, ClientSettings,
+    auth::login::AccessTokenLoginRequest,
+    secrets_manager::{ClientSecretsExt, secrets::SecretsGetRequest},
+};
+use error_stack::{Report, ResultExt};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::{Level, event};
+use url::Url;
+use uuid::Uuid;
+use zeroize::Zeroize;
+
+use crate::{
+    conf::{self, Tls},
+    secret::Secret,
+    source::{self, KeySource, cache::KeyCache},
+    tls,
+};
+
+#[derive(Error, Debug)]
+#[error("bitwarden client create error")]
+pub struct Error;
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+pub struct BitwardenSecret {
+    client: Client,
+    logged_in: Mutex<bool>,
+    token: Secret,
+    api_url: Url,
+    identity_url: Url,
+    secret_ids: Vec<Uuid>,
+    cache: Option<KeyCache>,
+    /// fetches answered from the cache since startup
+    cache_used: AtomicU64,
+    probe: reqwest::Client,
+}
+
+impl BitwardenSecret {
+    pub async fn new(cfg: &conf::Bitwarden) -> Result<Self> {
+        let setting = ClientSettings {
+            api_url: cfg.api_url.as_str().trim_end_matches('/').to_owned(),
+            identity_url: cfg.identity_url.as_str().trim_end_matches('/').to_owned(),
+            ..ClientSettings::default()
+        };
+        let client = Client::new(Some(setting));
+        let (_, probe) =
+            tls::http_client(&cfg.api_url, &Tls::default(), &[]).change_context(Error)?;
+
+        let logged_in = match login(&client, &cfg.token, &cfg.identity_url).await {
+            Ok(()) => true,
+            // the cached keys are still usable while bitwarden is down
+            Err(report) if cfg.cache.is_some() => {
+                event!(Level::WARN, "{report:?}");
+                false
+            }
+            Err(report) => return Err(report),
+        };
+
+        Ok(Self {
+            client,
+            logged_in: Mutex::new(logged_in),
+            token: cfg.token.clone(),
+            api_url: cfg.api_url.clone(),
+            identity_url: cfg.identity_url.clone(),
+            secret_ids: cfg.secret_ids.clone(),
+            cache: cfg.cache.as_ref().map(KeyCache::new),
+            cache_used: AtomicU64::new(0),
+            probe,
+        })
+    }
+
+    pub async fn get_secrets(&self) -> Result<Vec<Secret>> {
+        let mut logged_in = self.logged_in.lock().await;
+        if !*logged_in {
+            login(&self.client, &self.token, &self.identity_url).await?;
+            *logged_in = true;
+        }
+        drop(logged_in);
+
+        let input = SecretsGetRequest {
+            ids: self.secret_ids.clone(),
+        };
+        let secrets = self
+            .client
+            .secrets()
+            .get_by_ids(input)
+            .await
+            .change_context(Error)
+            .attach(format!(
+                "failed to get secrets from Bitwarden for ids: {:?}",
+                self.secret_ids
+            ))?;
+        let secrets = secrets
+            .data
+            .into_iter()
+            .map(|s| Secret::new(s.value))
+            .collect();
+        Ok(secrets)
+    }
+
+    // Bitwarden is reachable if both endpoints answer without a server
+    // error, the cache must not cover for a revoked token or missing secret
+    async fn reachable(&self) -> bool {
+        for url in [&self.api_url, &self.identity_url] {
+            let response = self
+                .probe
+                .get(url.clone())
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await;
+            match response {
+                Ok(response) if !response.status().is_server_error() => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+async fn login(client: &Client, token: &Secret, identity_url: &Url) -> Result<()> {
+    let mut token = AccessTokenLoginRequest {
+        access_token: token.expose().to_owned(),
+        state_file: None,
+    };
+    let login = client.auth().login_access_token(&token).await;
+    token.access_token.zeroize();
+    login
+        .change_context(Error)
+        .attach(format!("failed to login to Bitwarden at {identity_url}"))?;
+    Ok(())
+}
+
+#[async_trait]
+impl KeySource for BitwardenSecret {
+    fn name(&self) -> &str {
+        "bitwarden"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        let Some(cache) = &self.cache else {
+            return self
+                .get_secrets()
+                .await
+                .change_context(source::Error::FetchError);
+        };
+
+        match self.get_secrets().await {
+            Ok(keys) => {
+                if !keys.is_empty()
+                    && let Err(report) = cache.store(&keys).await
+                {
+                    event!(
+                        Level::WARN,
+                        "failed to update the bitwarden key cache: {report:?}"
+                    );
+                }
+                Ok(keys)
+            }
+            Err(report) if !self.reachable().await => {
+                let (keys, age) = cache
+                    .load()
+                    .await
+                    .change_context(source::Error::FetchError)
+                    .attach("bitwarden is unreachable and the key cache cannot be used")?;
+                // there is no metrics exporter, the running total is a field
+                // of every warning so log based alerts can count on it
+                let cache_used = self.cache_used.fetch_add(1, Ordering::Relaxed) + 1;
+                event!(
+                    Level::WARN,
+                    bitwarden_key_cache_used = cache_used,
+                    "running degraded, bitwarden is unreachable, using {} cached keys written {}s ago: {report:?}",
+                    keys.len(),
+                    age.as_secs()
+                );
+                Ok(keys)
+            }
+            Err(report) => Err(report.change_context(source::Error::FetchError)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes::cipher::{BlockEncryptMut, KeyIvInit, block_padding::Pkcs7};
+    use base64::{
+        Engine,
+        engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD},
+    };
+    use hmac::{Hmac, Mac};
+    use serde_json::json;
+    use sha2::Sha256;
+
+    use super::*;
+    use crate::{
+        conf::ExternalBitwarden,
+        testing::{self, Stub},
+    };
+
+    const CLIENT_ID: &str = "ec2c1d46-6a4b-4751-a310-af9601317f2d";
+    const ACCESS_TOKEN: &str = "0.ec2c1d46-6a4b-4751-a310-af9601317f2d.C2IgxjjLF7qSshsbwe8JGcbM075YXw:X8vbvA0bduihIDe/qrzIQQ==";
+    const ORGANIZATION_ID: &str = "2d1f8a44-3c1b-4b8e-9a57-0b5e6c3f2a11";
+    const SECRET_ID: &str = "2460335d-6b9f-43ac-8bd0-8ceaedcc279e";
+    const ORGANIZATION_KEY: [u8; 64] = [7; 64];
+
+    // The key the login payload is encrypted with, `derive_shareable_key`
+    // of bitwarden-crypto applied to the secret part of the access token
+    fn access_token_key() -> [u8; 64] {
+        let (_, secret) = ACCESS_TOKEN.split_once(':').unwrap();
+        let prk = Hmac::<Sha256>::new_from_slice(b"bitwarden-accesstoken")
+            .unwrap()
+            .chain_update(BASE64.decode(secret).unwrap())
+            .finalize()
+            .into_bytes();
+        let mut key = [0; 64];
+        hkdf::Hkdf::<Sha256>::from_prk(&prk)
+            .unwrap()
+            .expand(b"sm-access-token", &mut key)
+            .unwrap();
+        key
+    }
+
+    // A type 2 EncString, AES-256-CBC with HMAC-SHA256
+    fn encrypt(key: &[u8; 64], plaintext: &str) -> String {
+        let iv = [3; 16];
+        let data = cbc::Encryptor::<aes::Aes256>::new(key[..32].into(), &iv.into())
+            .encrypt_padded_vec_mut::<Pkcs7>(plaintext.as_bytes());
+        let mac = Hmac::<Sha256>::new_from_slice(&key[32..])
+            .unwrap()
+            .chain_update(iv)
+            .chain_update(&data)
+            .finalize()
+            .into_bytes();
+        format!(
+            "2.{}|{}|{}",
+            BASE64.encode(iv),
+            BASE64.encode(&data),
+            BASE64.encode(mac)
+        )
+    }
+
+    fn jwt() -> String {
+        let claims = json!({
+            "exp": 4102444800u64,
+            "sub": CLIENT_ID,
+            "organization": ORGANIZATION_ID,
+        });
+        format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"none"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        )
+    }
+
+    // Answers like the identity and api servers of a self-hosted instance
+    fn bitwarden() -> Stub {
+        Stub::serve(|request| {
+            if request.path.ends_with("/connect/token") {
+                let payload = json!({ "encryptionKey": BASE64.encode(ORGANIZATION_KEY) });
+                let body = json!({
+                    "access_token": jwt(),
+                    "expires_in": 3600,
+                    "token_type": "Bearer",
+                    "scope": "api.secrets",
+                    "encrypted_payload": encrypt(&access_token_key(), &payload.to_string()),
+                });
+                return testing::json(200, &body.to_string());
+            }
+
+            if request.path.ends_with("/secrets/get-by-ids") {
+                let body = json!({
+                    "object": "list",
+                    "data": [{
+                        "object": "secret",
+                        "id": SECRET_ID,
+                        "organizationId": ORGANIZATION_ID,
+                        "key": encrypt(&ORGANIZATION_KEY, "unseal-key-1"),
+                        "value": encrypt(&ORGANIZATION_KEY, "c2hhcmUtMQ=="),
+                        "note": encrypt(&ORGANIZATION_KEY, ""),
+                        "creationDate": "2025-01-01T00:00:00Z",
+                        "revisionDate": "2025-01-01T00:00:00Z",
+                        "projects": [],
+                    }],
+                });
+                return testing::json(200, &body.to_string());
+            }
+
+            testing::json(404, "{}")
+        })
+    }
+
+    fn config(external: ExternalBitwarden) -> conf::Bitwarden {
+        ExternalBitwarden {
+            bw_token: Some(Secret::new(ACCESS_TOKEN.to_owned())),
+            bw_secret_ids: Some(vec![SECRET_ID.parse().unwrap()]),
+            ..external
+        }
+        .try_into()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn self_hosted_host_derives_both_endpoints() {
+        let stub = bitwarden();
+        let cfg = config(ExternalBitwarden {
+            bw_host: Some(stub.url.clone()),
+            ..ExternalBitwarden::default()
+        });
+
+        let source = BitwardenSecret::new(&cfg).await.unwrap();
+        assert_eq!(stub.calls(), ["POST /identity/connect/token"]);
+
+        let login = &stub.requests()[0];
+        let form = String::from_utf8_lossy(&login.body);
+        assert!(form.contains("grant_type=client_credentials"));
+        assert!(form.contains(&format!("client_id={CLIENT_ID}")));
+
+        let keys = source.get_secrets().await.unwrap();
+        let keys: Vec<&str> = keys.iter().map(Secret::expose).collect();
+        assert_eq!(keys, ["c2hhcmUtMQ=="]);
+        assert_eq!(
+            stub.calls(),
+            [
+                "POST /identity/connect/token",
+                "POST /api/secrets/get-by-ids"
+            ]
+        );
+
+        let fetch = &stub.requests()[1];
+        assert_eq!(
+            fetch.header("authorization"),
+            Some(format!("Bearer {}", jwt()).as_str())
+        );
+        assert!(String::from_utf8_lossy(&fetch.body).contains(SECRET_ID));
+    }
+
+    #[tokio::test]
+    async fn explicit_urls_override_the_host() {
+        let stub = bitwarden();
+        let cfg = config(ExternalBitwarden {
+            bw_host: Some(Url::parse("https://vault.bitwarden.com").unwrap()),
+            bw_api_url: Some(stub.url.join("custom/api").unwrap()),
+            bw_identity_url: Some(stub.url.join("custom/identity").unwrap()),
+            ..ExternalBitwarden::default()
+        });
+
+        let source = BitwardenSecret::new(&cfg).await.unwrap();
+        source.get_secrets().await.unwrap();
+        assert_eq!(
+            stub.calls(),
+            [
+                "POST /custom/identity/connect/token",
+                "POST /custom/api/secrets/get-by-ids"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_login_fails_without_calling_the_api() {
+        let stub = Stub::serve(|_| testing::json(400, r#"{"error":"invalid_client"}"#));
+        let cfg = config(ExternalBitwarden {
+            bw_host: Some(stub.url.clone()),
+            ..ExternalBitwarden::default()
+        });
+
+        assert!(BitwardenSecret::new(&cfg).await.is_err());
+        assert_eq!(stub.calls(), ["POST /identity/connect/token"]);
+    }
+
+    // With a cache configured the source starts even though the stub
+    // refuses the login, as it would while bitwarden is down
+    async fn cached(host: Url) -> BitwardenSecret {
+        let cfg = config(ExternalBitwarden {
+            bw_host: Some(host),
+            bw_cache_path: Some("/var/cache/vault-unseal/keys.age".into()),
+            bw_cache_age_identity_file: Some("/etc/vault-unseal/identity.txt".into()),
+            ..ExternalBitwarden::default()
+        });
+        BitwardenSecret::new(&cfg).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn server_errors_make_bitwarden_unreachable() {
+        let stub = Stub::serve(|request| match request.path.as_str() {
+            "/identity" => testing::json(503, "{}"),
+            _ => testing::json(404, "{}"),
+        });
+        let source = cached(stub.url.clone()).await;
+        assert!(!source.reachable().await);
+        assert!(
+            stub.calls()
+                .ends_with(&["GET /api".to_owned(), "GET /identity".to_owned()])
+        );
+
+        // a client error still means the server is up
+        let stub = Stub::serve(|_| testing::json(401, "{}"));
+        assert!(cached(stub.url.clone()).await.reachable().await);
+    }
+
+    #[tokio::test]
+    async fn refused_connections_make_bitwarden_unreachable() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        drop(listener);
+
+        assert!(!cached(url).await.reachable().await);
+    }
+}
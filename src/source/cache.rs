@@ -0,0 +1,360 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+use aes_gcm::{
+    Aes256Gcm, KeyInit, Nonce,
+    aead::{Aead, AeadCore, OsRng},
+};
+use age::{Decryptor, Encryptor, IdentityFile};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use error_stack::{Report, ResultExt};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    conf::{self, AgeIdentity, CacheKey},
+    secret::Secret,
+    source::{self, age_file, keyring},
+};
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("key cache key error")]
+    KeyError,
+    #[error("key cache write error")]
+    WriteError,
+    #[error("key cache read error")]
+    ReadError,
+    #[error("key cache expired")]
+    Expired,
+}
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+const HEADER: &str = "# vault-unseal key cache written at";
+const NONCE_LEN: usize = 12;
+
+/// Unseal keys kept encrypted on disk for when their source is unreachable
+pub struct KeyCache {
+    cfg: conf::KeyCache,
+}
+
+impl KeyCache {
+    pub fn new(cfg: &conf::KeyCache) -> Self {
+        Self { cfg: cfg.clone() }
+    }
+
+    /// Encrypt the keys and replace the cache with them
+    pub async fn store(&self, keys: &[Secret]) -> Result<()> {
+        let cfg = self.cfg.clone();
+        let keys = keys.to_vec();
+
+        tokio::task::spawn_blocking(move || store(&cfg, &keys))
+            .await
+            .change_context(Error::WriteError)?
+    }
+
+    /// Decrypt the cached keys, returns them with their age unless they are
+    /// older than the configured max age
+    pub async fn load(&self) -> Result<(Vec<Secret>, Duration)> {
+        let cfg = self.cfg.clone();
+
+        tokio::task::spawn_blocking(move || load(&cfg))
+            .await
+            .change_context(Error::ReadError)?
+    }
+}
+
+fn store(cfg: &conf::KeyCache, keys: &[Secret]) -> Result<()> {
+    let mut plaintext = Zeroizing::new(format!("{HEADER} {}\n", now()));
+    for key in keys {
+        plaintext.push_str(key.expose());
+        plaintext.push('\n');
+    }
+    let ciphertext = encrypt(&cfg.key, plaintext.as_bytes())?;
+
+    // write next to the cache and rename, a crash never leaves half a file
+    let file_name = cfg.path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = cfg.path.with_file_name(format!(".{file_name}.tmp"));
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let write = || -> std::io::Result<()> {
+        let mut file = options.open(&tmp)?;
+        file.write_all(&ciphertext)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &cfg.path)
+    };
+    write()
+        .change_context(Error::WriteError)
+        .attach(format!("failed to write {}", cfg.path.display()))
+}
+
+fn load(cfg: &conf::KeyCache) -> Result<(Vec<Secret>, Duration)> {
+    let ciphertext = fs::read(&cfg.path)
+        .change_context(Error::ReadError)
+        .attach(format!("failed to read {}", cfg.path.display()))?;
+    let plaintext = decrypt(&cfg.key, &ciphertext)?;
+    let plaintext = std::str::from_utf8(&plaintext)
+        .change_context(Error::ReadError)
+        .attach(format!("{} is not a key cache", cfg.path.display()))?;
+
+    // the timestamp is inside the ciphertext, so it cannot be moved forward
+    // without the key
+    let written_at: u64 = plaintext
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix(HEADER))
+        .and_then(|written_at| written_at.trim().parse().ok())
+        .ok_or_else(|| {
+            Report::new(Error::ReadError)
+                .attach(format!("{} is not a key cache", cfg.path.display()))
+        })?;
+    let age = now().saturating_sub(written_at);
+    if age > cfg.max_age {
+        return Err(Report::new(Error::Expired).attach(format!(
+            "cached keys are {age}s old, max age is {}s",
+            cfg.max_age
+        )));
+    }
+
+    let keys = source::parse_keys(plaintext)
+        .change_context(Error::ReadError)
+        .attach(format!("invalid keys in {}", cfg.path.display()))?;
+    Ok((keys, Duration::from_secs(age)))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs())
+        .unwrap_or_default()
+}
+
+fn encrypt(key: &CacheKey, plaintext: &[u8]) -> Result<Vec<u8>> {
+    match key {
+        CacheKey::Age(identity) => age_encrypt(identity, plaintext),
+        CacheKey::Keyring(_) | CacheKey::Credential(_) => aes_encrypt(&cipher(key)?, plaintext),
+    }
+}
+
+fn decrypt(key: &CacheKey, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    match key {
+        CacheKey::Age(identity) => age_decrypt(identity, ciphertext),
+        CacheKey::Keyring(_) | CacheKey::Credential(_) => aes_decrypt(&cipher(key)?, ciphertext),
+    }
+}
+
+fn aes_encrypt(cipher: &Aes256Gcm, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| Report::new(Error::WriteError).attach("failed to encrypt the key cache"))?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+fn aes_decrypt(cipher: &Aes256Gcm, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    if ciphertext.len() < NONCE_LEN {
+        return Err(Report::new(Error::ReadError).attach("key cache is truncated"));
+    }
+    let (nonce, ciphertext) = ciphertext.split_at(NONCE_LEN);
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| {
+            Report::new(Error::ReadError)
+                .attach("failed to decrypt the key cache, it was written with another key")
+        })?;
+    Ok(Zeroizing::new(plaintext))
+}
+
+fn age_encrypt(identity: &Path, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let recipients = IdentityFile::from_file(identity.to_string_lossy().into_owned())
+        .change_context(Error::KeyError)
+        .attach(format!(
+            "failed to read age identity file {}",
+            identity.display()
+        ))?
+        .to_recipients()
+        .change_context(Error::KeyError)
+        .attach(format!("invalid age identity file {}", identity.display()))?;
+    let encryptor = Encryptor::with_recipients(
+        recipients
+            .iter()
+            .map(|recipient| recipient.as_ref() as &dyn age::Recipient),
+    )
+    .change_context(Error::KeyError)?;
+
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor
+        .wrap_output(&mut ciphertext)
+        .change_context(Error::WriteError)?;
+    writer
+        .write_all(plaintext)
+        .change_context(Error::WriteError)?;
+    writer.finish().change_context(Error::WriteError)?;
+    Ok(ciphertext)
+}
+
+fn age_decrypt(identity: &Path, ciphertext: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let identities = age_file::identities(&AgeIdentity::IdentityFile(identity.to_path_buf()))
+        .change_context(Error::KeyError)?;
+
+    let mut reader = Decryptor::new(ciphertext)
+        .change_context(Error::ReadError)
+        .attach("key cache is not an age file")?
+        .decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        .change_context(Error::ReadError)
+        .attach("failed to decrypt the key cache")?;
+
+    let mut plaintext = Zeroizing::new(Vec::new());
+    reader
+        .read_to_end(&mut plaintext)
+        .change_context(Error::ReadError)?;
+    Ok(plaintext)
+}
+
+// AES-256-GCM with a base64 key from the kernel keyring or a systemd
+// credential
+fn cipher(key: &CacheKey) -> Result<Aes256Gcm> {
+    let encoded = match key {
+        CacheKey::Keyring(cfg) => keyring::load(cfg).change_context(Error::KeyError)?,
+        CacheKey::Credential(name) => {
+            let dir = std::env::var_os("CREDENTIALS_DIRECTORY")
+                .map(PathBuf::from)
+                .ok_or_else(|| {
+                    Report::new(Error::KeyError).attach("CREDENTIALS_DIRECTORY is not set")
+                })?;
+            Zeroizing::new(
+                fs::read(dir.join(name))
+                    .change_context(Error::KeyError)
+                    .attach(format!("failed to read credential {name}"))?,
+            )
+        }
+        CacheKey::Age(_) => {
+            return Err(Report::new(Error::KeyError).attach("age identities are not aes keys"));
+        }
+    };
+
+    let encoded = std::str::from_utf8(&encoded)
+        .change_context(Error::KeyError)
+        .attach("key cache key is not base64")?;
+    let raw = Zeroizing::new(
+        BASE64
+            .decode(encoded.trim())
+            .change_context(Error::KeyError)
+            .attach("key cache key is not base64")?,
+    );
+    Aes256Gcm::new_from_slice(&raw).map_err(|_| {
+        Report::new(Error::KeyError)
+            .attach(format!("key cache key must be 32 bytes, got {}", raw.len()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use age::{secrecy::ExposeSecret, x25519};
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn age_cache(dir: &TempDir, max_age: u64) -> conf::KeyCache {
+        let identity = dir.path().join("identity.txt");
+        let secret = x25519::Identity::generate().to_string();
+        fs::write(&identity, format!("{}\n", secret.expose_secret())).unwrap();
+        conf::KeyCache {
+            path: dir.path().join("keys.cache"),
+            max_age,
+            key: CacheKey::Age(identity),
+        }
+    }
+
+    fn keys(keys: &[Secret]) -> Vec<&str> {
+        keys.iter().map(Secret::expose).collect()
+    }
+
+    #[test]
+    fn age_cache_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let cfg = age_cache(&dir, 3600);
+        let stored = [
+            Secret::new("unseal-key-1".to_owned()),
+            Secret::new("unseal-key-2".to_owned()),
+        ];
+        store(&cfg, &stored).unwrap();
+
+        let (cached, age) = load(&cfg).unwrap();
+        assert_eq!(keys(&cached), ["unseal-key-1", "unseal-key-2"]);
+        assert!(age < Duration::from_secs(60));
+
+        let mode = fs::metadata(&cfg.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let written = fs::read(&cfg.path).unwrap();
+        assert!(!written.windows(10).any(|w| w == b"unseal-key"));
+    }
+
+    #[test]
+    fn cache_older_than_max_age_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let cfg = age_cache(&dir, 3600);
+        let written_at = now() - 7200;
+        let plaintext = format!("{HEADER} {written_at}\nk1\n");
+        fs::write(&cfg.path, encrypt(&cfg.key, plaintext.as_bytes()).unwrap()).unwrap();
+
+        let report = load(&cfg).unwrap_err();
+        assert!(matches!(report.current_context(), Error::Expired));
+    }
+
+    #[test]
+    fn age_cache_of_another_identity_is_rejected() {
+        let dir = TempDir::new().unwrap();
+        let cfg = age_cache(&dir, 3600);
+        store(&cfg, &[Secret::new("k1".to_owned())]).unwrap();
+
+        let other = TempDir::new().unwrap();
+        let cfg = conf::KeyCache {
+            path: cfg.path,
+            ..age_cache(&other, 3600)
+        };
+        let report = load(&cfg).unwrap_err();
+        assert!(matches!(report.current_context(), Error::ReadError));
+    }
+
+    fn aes(key: u8) -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&[key; 32]).unwrap()
+    }
+
+    #[test]
+    fn aes_round_trip() {
+        let sealed = aes_encrypt(&aes(1), b"k1\nk2\n").unwrap();
+        assert_eq!(
+            aes_decrypt(&aes(1), &sealed).unwrap().as_slice(),
+            b"k1\nk2\n"
+        );
+    }
+
+    #[test]
+    fn aes_rejects_tampered_ciphertext_and_wrong_key() {
+        let sealed = aes_encrypt(&aes(1), b"k1\n").unwrap();
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(aes_decrypt(&aes(1), &tampered).is_err());
+        assert!(aes_decrypt(&aes(1), &sealed[..NONCE_LEN - 1]).is_err());
+        assert!(aes_decrypt(&aes(2), &sealed).is_err());
+    }
+}
@@ -0,0 +1,95 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use async_trait::async_trait;
+use error_stack::ResultExt;
+use tokio::process::Command;
+use tracing::instrument;
+use zeroize::Zeroizing;
+
+use crate::{
+    conf,
+    secret::Secret,
+    source::{
+        self, KeySource,
+        process::{self, Result},
+    },
+};
+
+/// Unseal keys printed to stdout by an external command, one per line or
+/// as a json array
+pub struct ExecCommand {
+    command: Vec<String>,
+    env: HashMap<String, Secret>,
+    working_dir: Option<PathBuf>,
+    timeout: Duration,
+    max_output_bytes: u64,
+}
+
+impl ExecCommand {
+    pub fn new(cfg: &conf::Exec) -> Self {
+        Self {
+            command: cfg.command.clone(),
+            env: cfg.env.clone(),
+            working_dir: cfg.working_dir.clone(),
+            timeout: Duration::from_secs(cfg.timeout),
+            max_output_bytes: cfg.max_output_bytes,
+        }
+    }
+
+    #[instrument(name = "exec::run", skip(self), fields(program = %self.command[0]))]
+    async fn run(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let mut command = Command::new(&self.command[0]);
+        command
+            .args(&self.command[1..])
+            .envs(self.env.iter().map(|(name, value)| (name, value.expose())));
+        if let Some(dir) = &self.working_dir {
+            command.current_dir(dir);
+        }
+
+        process::run(command, self.timeout, self.max_output_bytes).await
+    }
+}
+
+#[async_trait]
+impl KeySource for ExecCommand {
+    fn name(&self) -> &str {
+        "exec"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        let stdout = self.run().await.change_context(source::Error::FetchError)?;
+        let stdout = std::str::from_utf8(&stdout)
+            .change_context(source::Error::FetchError)
+            .attach("key command printed invalid utf-8")?;
+
+        source::parse_keys(stdout)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn env_values_reach_the_command_but_not_the_debug_output() {
+        let cfg = conf::Exec {
+            command: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                "echo $UNSEAL_KEY".to_owned(),
+            ],
+            env: HashMap::from([(
+                "UNSEAL_KEY".to_owned(),
+                Secret::new("unseal-key-1".to_owned()),
+            )]),
+            working_dir: None,
+            timeout: 5,
+            max_output_bytes: 1024,
+        };
+        assert!(!format!("{cfg:#?}").contains("unseal-key-1"));
+
+        let keys = ExecCommand::new(&cfg).fetch_keys().await.unwrap();
+        let keys: Vec<&str> = keys.iter().map(Secret::expose).collect();
+        assert_eq!(keys, ["unseal-key-1"]);
+    }
+}
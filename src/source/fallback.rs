@@ -0,0 +1,193 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use error_stack::Report;
+use thiserror::Error;
+use tokio::time::Instant;
+use tracing::{Level, event};
+
+use crate::{
+    conf,
+    secret::Secret,
+    source::{self, KeySource},
+};
+
+#[derive(Error, Debug)]
+#[error("every fallback key source failed")]
+pub struct Error;
+
+#[derive(Default)]
+struct Health {
+    failures: u32,
+    cooling_until: Option<Instant>,
+}
+
+/// Unseal keys from the first key source of an ordered list that provides
+/// any, a failed source is tried last until its cooldown is over
+pub struct Fallback {
+    members: Vec<Arc<dyn KeySource>>,
+    health: Mutex<Vec<Health>>,
+    cooldown: Duration,
+}
+
+impl Fallback {
+    pub async fn new(cfg: &conf::Fallback) -> source::Result<Self> {
+        let mut members = Vec::new();
+        for member in &cfg.sources {
+            members.push(Box::pin(source::from_config(member)).await?);
+        }
+
+        Ok(Self {
+            health: Mutex::new(members.iter().map(|_| Health::default()).collect()),
+            members,
+            cooldown: Duration::from_secs(cfg.cooldown),
+        })
+    }
+
+    // Healthy sources in the configured order, the ones cooling down are
+    // only tried as a last resort
+    fn order(&self) -> Vec<usize> {
+        let health = self.health.lock().unwrap();
+        let now = Instant::now();
+        let (ready, cooling): (Vec<usize>, Vec<usize>) = (0..self.members.len())
+            .partition(|&i| health[i].cooling_until.is_none_or(|until| until <= now));
+        ready.into_iter().chain(cooling).collect()
+    }
+
+    fn succeeded(&self, i: usize) {
+        let mut health = self.health.lock().unwrap();
+        if health[i].failures > 0 {
+            event!(
+                Level::INFO,
+                "key source {} recovered after {} failures",
+                self.members[i].name(),
+                health[i].failures
+            );
+        }
+        health[i] = Health::default();
+    }
+
+    fn failed(&self, i: usize, report: Report<Error>) {
+        let mut health = self.health.lock().unwrap();
+        health[i].failures += 1;
+        health[i].cooling_until = Some(Instant::now() + self.cooldown);
+        event!(
+            Level::WARN,
+            "key source {} failed {} times in a row, trying it last for {}s: {report:?}",
+            self.members[i].name(),
+            health[i].failures,
+            self.cooldown.as_secs()
+        );
+    }
+}
+
+#[async_trait]
+impl KeySource for Fallback {
+    fn name(&self) -> &str {
+        "fallback"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        self.fetch_keys_from().await.map(|(keys, _)| keys)
+    }
+
+    async fn fetch_keys_from(&self) -> source::Result<(Vec<Secret>, String)> {
+        for i in self.order() {
+            let member = &self.members[i];
+            let report = match member.fetch_keys_from().await {
+                Ok((keys, from)) if !keys.is_empty() => {
+                    self.succeeded(i);
+                    return Ok((keys, from));
+                }
+                Ok(_) => Report::new(Error).attach(format!("{} provided no keys", member.name())),
+                Err(report) => report
+                    .change_context(Error)
+                    .attach(format!("{} failed", member.name())),
+            };
+            self.failed(i, report);
+        }
+
+        let names: Vec<&str> = self.members.iter().map(|member| member.name()).collect();
+        Err(Report::new(Error)
+            .attach(format!("tried {}", names.join(", ")))
+            .change_context(source::Error::FetchError))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Keys;
+
+    const COOLDOWN: Duration = Duration::from_secs(60);
+
+    fn fallback(members: &[Arc<Keys>]) -> Fallback {
+        Fallback {
+            members: members
+                .iter()
+                .map(|member| member.clone() as Arc<dyn KeySource>)
+                .collect(),
+            health: Mutex::new(members.iter().map(|_| Health::default()).collect()),
+            cooldown: COOLDOWN,
+        }
+    }
+
+    async fn fetch(fallback: &Fallback) -> source::Result<String> {
+        let (keys, from) = fallback.fetch_keys_from().await?;
+        assert_eq!(keys[0].expose(), format!("{from}-key"));
+        Ok(from)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_source_is_tried_last_until_its_cooldown_is_over() {
+        let primary = Arc::new(Keys::failing("vault"));
+        let secondary = Arc::new(Keys::new("age_file", &["age_file-key"]));
+        let fallback = fallback(&[primary.clone(), secondary.clone()]);
+
+        assert_eq!(fetch(&fallback).await.unwrap(), "age_file");
+        assert_eq!((primary.fetches(), secondary.fetches()), (1, 1));
+
+        // cooling down, the secondary answers without asking the primary
+        tokio::time::advance(COOLDOWN - Duration::from_secs(1)).await;
+        assert_eq!(fetch(&fallback).await.unwrap(), "age_file");
+        assert_eq!((primary.fetches(), secondary.fetches()), (1, 2));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert_eq!(fetch(&fallback).await.unwrap(), "age_file");
+        assert_eq!((primary.fetches(), secondary.fetches()), (2, 3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_sources_are_demoted_to_the_end_of_the_order() {
+        let fallback = fallback(&[
+            Arc::new(Keys::failing("vault")),
+            Arc::new(Keys::failing("bitwarden")),
+            Arc::new(Keys::new("age_file", &["age_file-key"])),
+            Arc::new(Keys::new("keyring", &["keyring-key"])),
+        ]);
+        assert_eq!(fallback.order(), [0, 1, 2, 3]);
+
+        assert_eq!(fetch(&fallback).await.unwrap(), "age_file");
+        // cooling sources keep their configured order among themselves
+        assert_eq!(fallback.order(), [2, 3, 0, 1]);
+
+        tokio::time::advance(COOLDOWN).await;
+        assert_eq!(fallback.order(), [0, 1, 2, 3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cooling_sources_are_still_tried_as_a_last_resort() {
+        let primary = Arc::new(Keys::failing("vault"));
+        let secondary = Arc::new(Keys::failing("age_file"));
+        let fallback = fallback(&[primary.clone(), secondary.clone()]);
+
+        assert!(fetch(&fallback).await.is_err());
+        let report = fetch(&fallback).await.unwrap_err();
+        assert!(format!("{report:?}").contains("tried vault, age_file"));
+        assert_eq!((primary.fetches(), secondary.fetches()), (2, 2));
+        assert_eq!(fallback.health.lock().unwrap()[0].failures, 2);
+    }
+}
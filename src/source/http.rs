@@ -0,0 +1,268 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use reqwest::Method;
+use thiserror::Error;
+use url::Url;
+use zeroize::Zeroizing;
+
+use crate::{
+    conf::{self, HttpAuth, HttpMethod},
+    secret::Secret,
+    source::{self, JsonDocument, KeySource},
+    tls,
+};
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("http key source client error")]
+    ClientError,
+    #[error("http key source request error")]
+    RequestError,
+    #[error("http key source response error")]
+    ResponseError,
+}
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Unseal keys extracted with json pointers from the response of a secret
+/// broker, e.g. 1Password Connect
+pub struct HttpJson {
+    client: reqwest::Client,
+    url: Url,
+    cfg: conf::Http,
+}
+
+impl HttpJson {
+    pub fn new(cfg: &conf::Http) -> Result<Self> {
+        let (url, client) =
+            tls::http_client(&cfg.url, &cfg.tls, &[]).change_context(Error::ClientError)?;
+
+        Ok(Self {
+            client,
+            url,
+            cfg: cfg.clone(),
+        })
+    }
+
+    async fn request(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let method = match self.cfg.method {
+            HttpMethod::Get => Method::GET,
+            HttpMethod::Post => Method::POST,
+        };
+        let mut request = self
+            .client
+            .request(method, self.url.clone())
+            .timeout(Duration::from_secs(self.cfg.timeout))
+            .header(reqwest::header::ACCEPT, "application/json");
+
+        if let Some(body) = &self.cfg.body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.expose().to_owned());
+        }
+        request = match &self.cfg.auth {
+            HttpAuth::None => request,
+            HttpAuth::Bearer(token) => request.bearer_auth(token.expose()),
+            HttpAuth::Basic { username, password } => {
+                request.basic_auth(username, Some(password.expose()))
+            }
+        };
+
+        let mut response = request
+            .send()
+            .await
+            .change_context(Error::RequestError)
+            .attach(format!("request to {} failed", self.cfg.url))?;
+
+        // the body may echo secrets, only the status is reported
+        let status = response.status();
+        if !status.is_success() {
+            return Err(Report::new(Error::ResponseError)
+                .attach(format!("{} answered with {status}", self.cfg.url)));
+        }
+
+        let limit = self.cfg.max_response_bytes;
+        let too_large = || {
+            Report::new(Error::ResponseError).attach(format!(
+                "response of {} is larger than {limit} bytes",
+                self.cfg.url
+            ))
+        };
+        if response.content_length().is_some_and(|len| len > limit) {
+            return Err(too_large());
+        }
+
+        let mut body = Zeroizing::new(Vec::new());
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .change_context(Error::ResponseError)
+            .attach(format!("failed to read the response of {}", self.cfg.url))?
+        {
+            if (body.len() + chunk.len()) as u64 > limit {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        Ok(body)
+    }
+}
+
+#[async_trait]
+impl KeySource for HttpJson {
+    fn name(&self) -> &str {
+        "http"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        let body = self
+            .request()
+            .await
+            .change_context(source::Error::FetchError)?;
+        let mut document = JsonDocument(
+            serde_json::from_slice(&body)
+                .change_context(source::Error::FetchError)
+                .attach(format!("response of {} is not json", self.cfg.url))?,
+        );
+
+        let mut keys = Vec::new();
+        for pointer in &self.cfg.pointers {
+            keys.extend(document.take_keys(pointer)?);
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{
+        conf::Tls,
+        testing::{self, Stub},
+    };
+
+    fn config(stub: &Stub, pointers: &[&str]) -> conf::Http {
+        conf::Http {
+            url: stub.url.join("keys").unwrap(),
+            method: HttpMethod::Get,
+            body: None,
+            auth: HttpAuth::None,
+            tls: Tls::default(),
+            pointers: pointers.iter().map(|p| (*p).to_owned()).collect(),
+            timeout: 5,
+            max_response_bytes: 128,
+        }
+    }
+
+    async fn fetch(cfg: &conf::Http) -> source::Result<Vec<String>> {
+        let keys = HttpJson::new(cfg).unwrap().fetch_keys().await?;
+        Ok(keys.iter().map(|key| key.expose().to_owned()).collect())
+    }
+
+    fn request_error(report: Report<Error>) -> bool {
+        matches!(report.current_context(), Error::RequestError)
+    }
+
+    fn response_error(report: Report<Error>) -> bool {
+        matches!(report.current_context(), Error::ResponseError)
+    }
+
+    #[tokio::test]
+    async fn keys_are_taken_from_arrays_and_strings() {
+        let stub = Stub::serve(|_| {
+            testing::json(
+                200,
+                r#"{"shares":["key-1","key-2"],"fields":{"more":"key-3\nkey-4\n"}}"#,
+            )
+        });
+        let cfg = config(&stub, &["/shares", "/fields/more"]);
+
+        let keys = fetch(&cfg).await.unwrap();
+        assert_eq!(keys, ["key-1", "key-2", "key-3", "key-4"]);
+        assert_eq!(stub.calls(), ["GET /keys"]);
+    }
+
+    #[tokio::test]
+    async fn bearer_token_is_sent() {
+        let stub = Stub::serve(|_| testing::json(200, r#"{"keys":["key-1"]}"#));
+        let mut cfg = config(&stub, &["/keys"]);
+        cfg.auth = HttpAuth::Bearer(Secret::new("connect-token".to_owned()));
+
+        fetch(&cfg).await.unwrap();
+        assert_eq!(
+            stub.requests()[0].header("authorization"),
+            Some("Bearer connect-token")
+        );
+    }
+
+    #[tokio::test]
+    async fn basic_auth_and_body_are_sent() {
+        let stub = Stub::serve(|_| testing::json(200, r#"{"keys":["key-1"]}"#));
+        let mut cfg = config(&stub, &["/keys"]);
+        cfg.method = HttpMethod::Post;
+        cfg.body = Some(Secret::new(r#"{"item":"vault"}"#.to_owned()));
+        cfg.auth = HttpAuth::Basic {
+            username: "user".to_owned(),
+            password: Secret::new("pass".to_owned()),
+        };
+
+        fetch(&cfg).await.unwrap();
+        let request = &stub.requests()[0];
+        assert_eq!(stub.calls(), ["POST /keys"]);
+        assert_eq!(request.header("authorization"), Some("Basic dXNlcjpwYXNz"));
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        assert_eq!(request.body, br#"{"item":"vault"}"#);
+    }
+
+    #[tokio::test]
+    async fn error_status_is_rejected() {
+        let stub = Stub::serve(|_| testing::json(403, r#"{"keys":["key-1"]}"#));
+        let cfg = config(&stub, &["/keys"]);
+
+        let report = HttpJson::new(&cfg).unwrap().request().await.unwrap_err();
+        assert!(response_error(report));
+    }
+
+    #[tokio::test]
+    async fn content_length_over_the_limit_is_rejected() {
+        let body = format!(r#"{{"keys":["{}"]}}"#, "k".repeat(128));
+        let stub = Stub::serve(move |_| testing::json(200, &body));
+        let cfg = config(&stub, &["/keys"]);
+
+        let report = HttpJson::new(&cfg).unwrap().request().await.unwrap_err();
+        assert!(response_error(report));
+    }
+
+    #[tokio::test]
+    async fn chunked_body_over_the_limit_is_rejected() {
+        let stub =
+            Stub::serve(|_| testing::chunked(200, &[r#"{"keys":[""#, &"k".repeat(128), r#""]}"#]));
+        let cfg = config(&stub, &["/keys"]);
+
+        let report = HttpJson::new(&cfg).unwrap().request().await.unwrap_err();
+        assert!(response_error(report));
+
+        let stub = Stub::serve(|_| testing::chunked(200, &[r#"{"keys":["#, r#""key-1"]}"#]));
+        let cfg = config(&stub, &["/keys"]);
+        assert_eq!(fetch(&cfg).await.unwrap(), ["key-1"]);
+    }
+
+    #[tokio::test]
+    async fn slow_server_times_out() {
+        let stub = Stub::serve(|_| {
+            thread::sleep(Duration::from_secs(3));
+            testing::json(200, r#"{"keys":["key-1"]}"#)
+        });
+        let mut cfg = config(&stub, &["/keys"]);
+        cfg.timeout = 1;
+
+        let report = HttpJson::new(&cfg).unwrap().request().await.unwrap_err();
+        assert!(request_error(report));
+    }
+}
@@ -0,0 +1,164 @@
+use std::{fs::File, path::Path};
+
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use keepass::{
+    Database, DatabaseKey,
+    db::{Entry, Group, Node},
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    conf::{self, KeepassPassword},
+    secret::Secret,
+    source::{self, KeySource},
+};
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("keepass credential error")]
+    CredentialError,
+    #[error("keepass open error")]
+    OpenError,
+    #[error("keepass entry error")]
+    EntryError,
+}
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Unseal keys kept in the entries of a local KeePass (kdbx4) database
+pub struct KeepassDatabase {
+    cfg: conf::Keepass,
+}
+
+impl KeepassDatabase {
+    pub fn new(cfg: &conf::Keepass) -> Self {
+        Self { cfg: cfg.clone() }
+    }
+}
+
+#[async_trait]
+impl KeySource for KeepassDatabase {
+    fn name(&self) -> &str {
+        "keepass"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        let cfg = self.cfg.clone();
+
+        // the kdf is deliberately slow, keep it off the runtime
+        tokio::task::spawn_blocking(move || read_keys(&cfg))
+            .await
+            .change_context(source::Error::FetchError)?
+            .change_context(source::Error::FetchError)
+    }
+}
+
+fn read_keys(cfg: &conf::Keepass) -> Result<Vec<Secret>> {
+    let db = open(cfg)?;
+
+    let group = cfg.group.iter().try_fold(&db.root, |group, name| {
+        child_group(group, name).ok_or_else(|| {
+            Report::new(Error::EntryError)
+                .attach(format!("group {} not found", cfg.group.join("/")))
+        })
+    })?;
+
+    let mut entries: Vec<&Entry> = group
+        .children
+        .iter()
+        .filter_map(|node| match node {
+            Node::Entry(entry) => Some(entry),
+            Node::Group(_) => None,
+        })
+        .collect();
+
+    if cfg.titles.is_empty() {
+        entries.sort_by_key(|entry| entry.get_title().unwrap_or_default());
+    } else {
+        entries = cfg
+            .titles
+            .iter()
+            .map(|title| {
+                entries
+                    .iter()
+                    .find(|entry| entry.get_title() == Some(title.as_str()))
+                    .copied()
+                    .ok_or_else(|| {
+                        Report::new(Error::EntryError).attach(format!(
+                            "entry {title} not found in group {}",
+                            cfg.group.join("/")
+                        ))
+                    })
+            })
+            .collect::<Result<_>>()?;
+    }
+
+    let mut keys = Vec::new();
+    for entry in entries {
+        let title = entry.get_title().unwrap_or_default();
+        let value = entry.get(&cfg.field).ok_or_else(|| {
+            Report::new(Error::EntryError)
+                .attach(format!("entry {title} has no field {}", cfg.field))
+        })?;
+        keys.extend(
+            source::parse_keys(value)
+                .change_context(Error::EntryError)
+                .attach(format!("invalid keys in entry {title}"))?,
+        );
+    }
+
+    Ok(keys)
+}
+
+fn open(cfg: &conf::Keepass) -> Result<Database> {
+    let mut key = DatabaseKey::new();
+
+    let password = match &cfg.password {
+        Some(KeepassPassword::File(path)) => Some(Zeroizing::new(
+            std::fs::read_to_string(path)
+                .change_context(Error::CredentialError)
+                .attach(format!(
+                    "failed to read keepass password file {}",
+                    path.display()
+                ))?,
+        )),
+        Some(KeepassPassword::Env(var)) => Some(Zeroizing::new(
+            std::env::var(var)
+                .change_context(Error::CredentialError)
+                .attach(format!("failed to read keepass password from ${var}"))?,
+        )),
+        None => None,
+    };
+    if let Some(password) = &password {
+        key = key.with_password(password.trim_end_matches(['\r', '\n']));
+    }
+
+    if let Some(path) = &cfg.key_file {
+        let mut key_file = open_file(path).change_context(Error::CredentialError)?;
+        key = key
+            .with_keyfile(&mut key_file)
+            .change_context(Error::CredentialError)
+            .attach(format!("invalid keepass key file {}", path.display()))?;
+    }
+
+    let mut file = open_file(&cfg.path)?;
+    Database::open(&mut file, key)
+        .change_context(Error::OpenError)
+        .attach(format!("failed to open {}", cfg.path.display()))
+}
+
+fn open_file(path: &Path) -> Result<File> {
+    File::open(path)
+        .change_context(Error::OpenError)
+        .attach(format!("failed to open {}", path.display()))
+}
+
+fn child_group<'a>(group: &'a Group, name: &str) -> Option<&'a Group> {
+    group.children.iter().find_map(|node| match node {
+        Node::Group(child) if child.name == name => Some(child),
+        _ => None,
+    })
+}
@@ -0,0 +1,126 @@
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    conf::{self, KeyringKind},
+    secret::Secret,
+    source::{self, KeySource},
+};
+
+#[derive(Error, Debug)]
+#[error("kernel keyring error")]
+pub struct Error;
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Unseal keys loaded into the kernel keyring by `vault-unseal keyring load`,
+/// stored as a single user key holding one key per line
+pub struct KernelKeyring {
+    keyring: KeyringKind,
+    description: String,
+}
+
+impl KernelKeyring {
+    pub fn new(cfg: &conf::Keyring) -> Self {
+        Self {
+            keyring: cfg.keyring,
+            description: cfg.description.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl KeySource for KernelKeyring {
+    fn name(&self) -> &str {
+        "keyring"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        let payload =
+            read(self.keyring, &self.description).change_context(source::Error::FetchError)?;
+        let payload = std::str::from_utf8(&payload)
+            .change_context(source::Error::FetchError)
+            .attach("keyring payload is not utf-8")?;
+
+        source::parse_keys(payload)
+    }
+}
+
+/// Store `keys` in the configured keyring, replacing the ones loaded before
+pub fn store(cfg: &conf::Keyring, keys: &[Secret]) -> Result<()> {
+    let mut payload = Zeroizing::new(String::new());
+    for key in keys {
+        payload.push_str(key.expose());
+        payload.push('\n');
+    }
+
+    write(cfg.keyring, &cfg.description, payload.as_bytes())
+}
+
+/// Read the raw payload of the configured key
+pub fn load(cfg: &conf::Keyring) -> Result<Zeroizing<Vec<u8>>> {
+    read(cfg.keyring, &cfg.description)
+}
+
+#[cfg(target_os = "linux")]
+fn open(keyring: KeyringKind, create: bool) -> Result<linux_keyutils::KeyRing> {
+    use linux_keyutils::{KeyRing, KeyRingIdentifier};
+
+    let ring = match keyring {
+        KeyringKind::Session => KeyRing::from_special_id(KeyRingIdentifier::Session, create),
+        KeyringKind::User => KeyRing::from_special_id(KeyRingIdentifier::User, create),
+        // linked into the process keyring only, the kernel keeps it alive
+        // across daemon restarts until persistent_keyring_expiry
+        KeyringKind::Persistent => KeyRing::get_persistent(KeyRingIdentifier::Process),
+    };
+    ring.change_context(Error)
+        .attach(format!("failed to open the {keyring:?} keyring"))
+}
+
+#[cfg(target_os = "linux")]
+fn read(keyring: KeyringKind, description: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let key = open(keyring, false)?
+        .search(description)
+        .change_context(Error)
+        .attach(format!(
+            "no key {description} in the {keyring:?} keyring, run `vault-unseal keyring load`"
+        ))?;
+
+    let payload = key
+        .read_to_vec()
+        .change_context(Error)
+        .attach(format!("failed to read key {description}"))?;
+    Ok(Zeroizing::new(payload))
+}
+
+#[cfg(target_os = "linux")]
+fn write(keyring: KeyringKind, description: &str, payload: &[u8]) -> Result<()> {
+    use linux_keyutils::{KeyPermissionsBuilder, Permission};
+
+    let key = open(keyring, true)?
+        .add_key(description, payload)
+        .change_context(Error)
+        .attach(format!("failed to add key {description}"))?;
+
+    // the daemon usually runs in its own session, so it only holds the key
+    // through the uid and needs read access beyond possession
+    let perms = KeyPermissionsBuilder::builder()
+        .posessor(Permission::ALL)
+        .user(Permission::VIEW | Permission::READ | Permission::SEARCH)
+        .build();
+    key.set_perms(perms)
+        .change_context(Error)
+        .attach(format!("failed to set permissions of key {description}"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read(_keyring: KeyringKind, _description: &str) -> Result<Zeroizing<Vec<u8>>> {
+    Err(Report::new(Error).attach("the kernel keyring is only supported on linux"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write(_keyring: KeyringKind, _description: &str, _payload: &[u8]) -> Result<()> {
+    Err(Report::new(Error).attach("the kernel keyring is only supported on linux"))
+}
@@ -0,0 +1,269 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use error_stack::{Report, ResultExt};
+use serde::Deserialize;
+use thiserror::Error;
+use url::Url;
+use zeroize::Zeroizing;
+
+use crate::{
+    conf,
+    secret::Secret,
+    source::{self, KeySource},
+    tls,
+};
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("kubernetes client error")]
+    ClientError,
+    #[error("kubernetes request error")]
+    RequestError,
+    #[error("kubernetes secret error")]
+    SecretError,
+}
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Only the data of a `v1/Secret` is needed
+#[derive(Deserialize)]
+struct KubernetesSecret {
+    #[serde(default)]
+    data: BTreeMap<String, Secret>,
+}
+
+/// Unseal keys kept in a Kubernetes Secret, read with the pod's service
+/// account
+pub struct KubernetesSecretSource {
+    client: reqwest::Client,
+    url: Url,
+    cfg: conf::Kubernetes,
+}
+
+impl KubernetesSecretSource {
+    pub fn new(cfg: &conf::Kubernetes) -> Result<Self> {
+        let (api_server, client) =
+            tls::http_client(&cfg.api_server, &cfg.tls, &[]).change_context(Error::ClientError)?;
+        let url = api_server
+            .join(&format!(
+                "api/v1/namespaces/{}/secrets/{}",
+                cfg.namespace, cfg.name
+            ))
+            .change_context(Error::ClientError)?;
+
+        Ok(Self {
+            client,
+            url,
+            cfg: cfg.clone(),
+        })
+    }
+
+    async fn read_keys(&self) -> Result<Vec<Secret>> {
+        // projected tokens are rotated by the kubelet, read it every time
+        let token = Zeroizing::new(
+            std::fs::read_to_string(&self.cfg.token_file)
+                .change_context(Error::ClientError)
+                .attach(format!(
+                    "failed to read service account token {}",
+                    self.cfg.token_file.display()
+                ))?,
+        );
+
+        let response = self
+            .client
+            .get(self.url.clone())
+            .bearer_auth(token.trim())
+            .timeout(Duration::from_secs(self.cfg.timeout))
+            .send()
+            .await
+            .change_context(Error::RequestError)
+            .attach(format!("request to {} failed", self.cfg.api_server))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(Report::new(Error::RequestError).attach(format!(
+                "reading secret {}/{} answered with {status}",
+                self.cfg.namespace, self.cfg.name
+            )));
+        }
+
+        let body = Zeroizing::new(
+            response
+                .bytes()
+                .await
+                .change_context(Error::RequestError)?
+                .to_vec(),
+        );
+        let mut secret: KubernetesSecret = serde_json::from_slice(&body)
+            .change_context(Error::SecretError)
+            .attach("invalid secret response")?;
+
+        // every data key in name order if none are configured
+        let values: Vec<(String, Secret)> = if self.cfg.keys.is_empty() {
+            secret.data.into_iter().collect()
+        } else {
+            self.cfg
+                .keys
+                .iter()
+                .map(|key| {
+                    secret
+                        .data
+                        .remove(key)
+                        .map(|value| (key.clone(), value))
+                        .ok_or_else(|| {
+                            Report::new(Error::SecretError).attach(format!(
+                                "secret {}/{} has no key {key}",
+                                self.cfg.namespace, self.cfg.name
+                            ))
+                        })
+                })
+                .collect::<Result<_>>()?
+        };
+
+        let mut keys = Vec::new();
+        for (key, value) in values {
+            let decoded = Zeroizing::new(
+                BASE64
+                    .decode(value.expose())
+                    .change_context(Error::SecretError)
+                    .attach(format!("{key} is not base64"))?,
+            );
+            let decoded = std::str::from_utf8(&decoded)
+                .change_context(Error::SecretError)
+                .attach(format!("{key} is not utf-8"))?;
+            keys.extend(
+                source::parse_keys(decoded)
+                    .change_context(Error::SecretError)
+                    .attach(format!("invalid keys in {key}"))?,
+            );
+        }
+
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl KeySource for KubernetesSecretSource {
+    fn name(&self) -> &str {
+        "kubernetes"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        self.read_keys()
+            .await
+            .change_context(source::Error::FetchError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::{
+        conf::Tls,
+        testing::{self, Stub},
+    };
+
+    const SECRET_PATH: &str = "/api/v1/namespaces/vault/secrets/vault-unseal-keys";
+
+    fn secret(data: &[(&str, &str)]) -> String {
+        let data: serde_json::Map<_, _> = data
+            .iter()
+            .map(|(key, value)| ((*key).to_owned(), json!(BASE64.encode(value))))
+            .collect();
+        json!({ "kind": "Secret", "apiVersion": "v1", "data": data }).to_string()
+    }
+
+    fn config(stub: &Stub, dir: &TempDir, keys: &[&str]) -> conf::Kubernetes {
+        let token_file = dir.path().join("token");
+        fs::write(&token_file, "service-account-token\n").unwrap();
+        conf::Kubernetes {
+            api_server: stub.url.clone(),
+            namespace: "vault".to_owned(),
+            name: "vault-unseal-keys".to_owned(),
+            keys: keys.iter().map(|key| (*key).to_owned()).collect(),
+            token_file,
+            tls: Tls::default(),
+            timeout: 5,
+        }
+    }
+
+    async fn read_keys(cfg: &conf::Kubernetes) -> Result<Vec<String>> {
+        let keys = KubernetesSecretSource::new(cfg)?.read_keys().await?;
+        Ok(keys.iter().map(|key| key.expose().to_owned()).collect())
+    }
+
+    #[tokio::test]
+    async fn configured_keys_are_read_in_order_with_the_token() {
+        let body = secret(&[("key1", "k1"), ("key2", "k2\nk3\n"), ("other", "x")]);
+        let stub = Stub::serve(move |_| testing::json(200, &body));
+        let dir = TempDir::new().unwrap();
+
+        let keys = read_keys(&config(&stub, &dir, &["key2", "key1"]))
+            .await
+            .unwrap();
+        assert_eq!(keys, ["k2", "k3", "k1"]);
+
+        assert_eq!(stub.calls(), [format!("GET {SECRET_PATH}")]);
+        assert_eq!(
+            stub.requests()[0].header("authorization"),
+            Some("Bearer service-account-token")
+        );
+    }
+
+    #[tokio::test]
+    async fn every_key_is_read_in_name_order_if_none_are_configured() {
+        let body = secret(&[("b", "k2"), ("a", r#"["k1"]"#), ("c", "k3")]);
+        let stub = Stub::serve(move |_| testing::json(200, &body));
+        let dir = TempDir::new().unwrap();
+
+        let keys = read_keys(&config(&stub, &dir, &[])).await.unwrap();
+        assert_eq!(keys, ["k1", "k2", "k3"]);
+    }
+
+    #[tokio::test]
+    async fn missing_key_and_invalid_base64_are_rejected() {
+        let body = secret(&[("key1", "k1")]);
+        let stub = Stub::serve(move |_| testing::json(200, &body));
+        let dir = TempDir::new().unwrap();
+        let report = read_keys(&config(&stub, &dir, &["key1", "key2"]))
+            .await
+            .unwrap_err();
+        assert!(matches!(report.current_context(), Error::SecretError));
+
+        let stub = Stub::serve(|_| testing::json(200, r#"{"data":{"key1":"not base64!"}}"#));
+        let report = read_keys(&config(&stub, &dir, &[])).await.unwrap_err();
+        assert!(matches!(report.current_context(), Error::SecretError));
+    }
+
+    #[tokio::test]
+    async fn error_status_is_rejected() {
+        let stub = Stub::serve(|_| testing::json(403, r#"{"kind":"Status"}"#));
+        let dir = TempDir::new().unwrap();
+        let report = read_keys(&config(&stub, &dir, &[])).await.unwrap_err();
+        assert!(matches!(report.current_context(), Error::RequestError));
+    }
+
+    #[tokio::test]
+    async fn slow_api_server_times_out() {
+        let stub = Stub::serve(|_| {
+            thread::sleep(Duration::from_secs(3));
+            testing::json(200, r#"{"data":{}}"#)
+        });
+        let dir = TempDir::new().unwrap();
+        let cfg = conf::Kubernetes {
+            timeout: 1,
+            ..config(&stub, &dir, &[])
+        };
+
+        let report = read_keys(&cfg).await.unwrap_err();
+        assert!(matches!(report.current_context(), Error::RequestError));
+    }
+}
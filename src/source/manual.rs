@@ -0,0 +1,78 @@
+use std::io::{IsTerminal, Read};
+
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    secret::Secret,
+    source::{self, KeySource},
+};
+
+#[derive(Error, Debug)]
+#[error("manual key entry error")]
+pub struct Error;
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Unseal keys entered by an operator, handed out as they are
+pub struct ManualKeys {
+    keys: Vec<Secret>,
+}
+
+impl ManualKeys {
+    pub fn new(keys: &[Secret]) -> Self {
+        Self {
+            keys: keys.to_vec(),
+        }
+    }
+}
+
+#[async_trait]
+impl KeySource for ManualKeys {
+    fn name(&self) -> &str {
+        "manual"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        Ok(self.keys.clone())
+    }
+}
+
+/// Read unseal keys from stdin, at a no-echo prompt until an empty line when
+/// stdin is a terminal, otherwise as piped with [`source::parse_keys`]
+pub fn read_keys() -> Result<Vec<Secret>> {
+    let mut stdin = std::io::stdin();
+
+    let keys = if stdin.is_terminal() {
+        let mut keys = Vec::new();
+        loop {
+            let share = Zeroizing::new(
+                rpassword::prompt_password(format!(
+                    "unseal key share {} (empty to finish): ",
+                    keys.len() + 1
+                ))
+                .change_context(Error)
+                .attach("failed to read the unseal key share")?,
+            );
+            if share.trim().is_empty() {
+                break;
+            }
+            keys.push(Secret::new(share.trim().to_owned()));
+        }
+        keys
+    } else {
+        let mut input = Zeroizing::new(String::new());
+        stdin
+            .read_to_string(&mut input)
+            .change_context(Error)
+            .attach("failed to read unseal keys from stdin")?;
+        source::parse_keys(&input).change_context(Error)?
+    };
+
+    if keys.is_empty() {
+        return Err(Report::new(Error).attach("no unseal keys entered"));
+    }
+    Ok(keys)
+}
@@ -0,0 +1,151 @@
+use std::{process::Stdio, time::Duration};
+
+use error_stack::{Report, ResultExt};
+use thiserror::Error;
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt},
+    process::Command,
+};
+use tracing::{Level, event};
+use zeroize::Zeroizing;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("key command spawn error")]
+    SpawnError,
+    #[error("key command timed out")]
+    Timeout,
+    #[error("key command failed")]
+    ExitError,
+    #[error("key command output too large")]
+    OutputTooLarge,
+}
+
+pub type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Only the start of stderr is logged, the rest is discarded
+const STDERR_LIMIT: u64 = 64 * 1024;
+
+/// Run a helper that prints unseal keys and return its stdout, the helper
+/// is killed if it does not exit within `timeout` or prints more than
+/// `max_output_bytes`
+pub async fn run(
+    mut command: Command,
+    timeout: Duration,
+    max_output_bytes: u64,
+) -> Result<Zeroizing<Vec<u8>>> {
+    let program = command
+        .as_std()
+        .get_program()
+        .to_string_lossy()
+        .into_owned();
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // the child is killed when the timeout or an error drops it
+        .kill_on_drop(true);
+
+    let mut child = command
+        .spawn()
+        .change_context(Error::SpawnError)
+        .attach(format!("failed to run {program}"))?;
+    let (Some(stdout), Some(stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        return Err(Report::new(Error::SpawnError).attach(format!("no output pipes for {program}")));
+    };
+
+    let output = async {
+        let (stdout, stderr) =
+            tokio::try_join!(read_stdout(stdout, max_output_bytes), read_stderr(stderr))?;
+        let status = child.wait().await.change_context(Error::SpawnError)?;
+        Ok::<_, Report<Error>>((status, stdout, stderr))
+    };
+    let (status, stdout, stderr) = tokio::time::timeout(timeout, output)
+        .await
+        .change_context(Error::Timeout)
+        .attach(format!(
+            "{program} did not exit within {}s",
+            timeout.as_secs()
+        ))?
+        .attach(format!("failed to read the output of {program}"))?;
+
+    // stderr is kept in the span so helper diagnostics end up in the log
+    let failed = !status.success();
+    for line in String::from_utf8_lossy(&stderr).lines() {
+        if failed {
+            event!(Level::WARN, stderr = %line);
+        } else {
+            event!(Level::DEBUG, stderr = %line);
+        }
+    }
+
+    if failed {
+        let report =
+            Report::new(Error::ExitError).attach(format!("{program} exited with {status}"));
+        return Err(report);
+    }
+
+    Ok(stdout)
+}
+
+// Stop reading as soon as the helper prints more than the limit, it is
+// killed without waiting for it to exit
+async fn read_stdout(stdout: impl AsyncRead + Unpin, limit: u64) -> Result<Zeroizing<Vec<u8>>> {
+    let mut output = Zeroizing::new(Vec::new());
+    stdout
+        .take(limit + 1)
+        .read_to_end(&mut output)
+        .await
+        .change_context(Error::SpawnError)?;
+
+    if output.len() as u64 > limit {
+        return Err(Report::new(Error::OutputTooLarge)
+            .attach(format!("printed more than {limit} bytes to stdout")));
+    }
+    Ok(output)
+}
+
+async fn read_stderr(mut stderr: impl AsyncRead + Unpin) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    (&mut stderr)
+        .take(STDERR_LIMIT)
+        .read_to_end(&mut output)
+        .await
+        .change_context(Error::SpawnError)?;
+    io::copy(&mut stderr, &mut io::sink())
+        .await
+        .change_context(Error::SpawnError)?;
+    Ok(output)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn sh(script: &str) -> Command {
+        let mut command = Command::new("sh");
+        command.args(["-c", script]);
+        command
+    }
+
+    #[tokio::test]
+    async fn stdout_over_the_limit_is_rejected_without_waiting() {
+        let report = run(sh("yes key-1"), Duration::from_secs(5), 1024)
+            .await
+            .unwrap_err();
+        assert!(matches!(report.current_context(), Error::OutputTooLarge));
+    }
+
+    #[tokio::test]
+    async fn long_stderr_does_not_block_the_helper() {
+        let stdout = run(
+            sh("head -c 200000 /dev/zero | tr '\\0' x >&2; echo key-1"),
+            Duration::from_secs(5),
+            1024,
+        )
+        .await
+        .unwrap();
+        assert_eq!(stdout.as_slice(), b"key-1\n");
+    }
+}
@@ -0,0 +1,199 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use futures::future;
+use thiserror::Error;
+use tracing::{Level, event};
+
+use crate::{
+    conf::QuorumSource,
+    secret::Secret,
+    source::{self, KeySource},
+};
+
+#[derive(Error, Debug)]
+#[error("key source quorum not met")]
+pub struct Error;
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+struct Member {
+    source: Arc<dyn KeySource>,
+    min: u64,
+}
+
+/// Unseal keys combined from several independent key sources, every source
+/// has to provide its minimum number of distinct shares or no key is handed
+/// out at all
+pub struct Quorum {
+    members: Vec<Member>,
+}
+
+impl Quorum {
+    pub async fn new(cfg: &[QuorumSource]) -> source::Result<Self> {
+        let mut members = Vec::new();
+        for member in cfg {
+            members.push(Member {
+                source: Box::pin(source::from_config(&member.source)).await?,
+                min: member.min,
+            });
+        }
+
+        Ok(Self { members })
+    }
+
+    async fn combine(&self) -> Result<Vec<Secret>> {
+        let fetched =
+            future::join_all(self.members.iter().map(|member| member.source.fetch_keys())).await;
+
+        let mut shares: Vec<Vec<Secret>> = Vec::new();
+        for (member, keys) in self.members.iter().zip(fetched) {
+            let name = member.source.name();
+            let keys = keys
+                .change_context(Error)
+                .attach(format!("{name} did not provide its shares"))?;
+
+            // a share held by two providers only counts for the first one
+            let mut distinct: Vec<Secret> = Vec::new();
+            for key in keys {
+                let seen = shares
+                    .iter()
+                    .flatten()
+                    .chain(distinct.iter())
+                    .any(|other| other.expose() == key.expose());
+                if seen {
+                    event!(
+                        Level::WARN,
+                        "{} provided a share already counted for another source",
+                        name
+                    );
+                    continue;
+                }
+                distinct.push(key);
+            }
+
+            if (distinct.len() as u64) < member.min {
+                return Err(Report::new(Error).attach(format!(
+                    "{name} provided {} distinct shares, the quorum requires {}",
+                    distinct.len(),
+                    member.min
+                )));
+            }
+            shares.push(distinct);
+        }
+
+        // interleave the required shares so that the shares the worker
+        // submits first come from every source, extra shares go last
+        let rounds = self.members.iter().map(|member| member.min).max();
+        let mut keys = Vec::new();
+        for round in 0..rounds.unwrap_or(0) as usize {
+            for (member, distinct) in self.members.iter().zip(&shares) {
+                if round < member.min as usize {
+                    keys.push(distinct[round].clone());
+                }
+            }
+        }
+        for (member, distinct) in self.members.iter().zip(&shares) {
+            keys.extend(distinct[member.min as usize..].iter().cloned());
+        }
+
+        event!(
+            Level::DEBUG,
+            "key source quorum met with {} shares from {}",
+            keys.len(),
+            self.members
+                .iter()
+                .map(|member| member.source.name())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl KeySource for Quorum {
+    fn name(&self) -> &str {
+        "quorum"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        self.combine()
+            .await
+            .change_context(source::Error::FetchError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Keys;
+
+    fn quorum(members: Vec<(Keys, u64)>) -> Quorum {
+        Quorum {
+            members: members
+                .into_iter()
+                .map(|(source, min)| Member {
+                    source: Arc::new(source),
+                    min,
+                })
+                .collect(),
+        }
+    }
+
+    async fn combine(members: Vec<(Keys, u64)>) -> Result<Vec<String>> {
+        let keys = quorum(members).combine().await?;
+        Ok(keys.iter().map(|key| key.expose().to_owned()).collect())
+    }
+
+    #[tokio::test]
+    async fn required_shares_are_interleaved_before_the_extra_ones() {
+        let keys = combine(vec![
+            (Keys::new("bitwarden", &["b1", "b2", "b3"]), 2),
+            (Keys::new("age_file", &["a1", "a2"]), 1),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(keys, ["b1", "a1", "b2", "b3", "a2"]);
+    }
+
+    #[tokio::test]
+    async fn shares_held_by_two_sources_count_once() {
+        let keys = combine(vec![
+            (Keys::new("bitwarden", &["s1", "s2"]), 2),
+            (Keys::new("age_file", &["s2", "s3"]), 1),
+        ])
+        .await
+        .unwrap();
+        assert_eq!(keys, ["s1", "s3", "s2"]);
+
+        // the copy leaves age_file without a share of its own
+        let report = combine(vec![
+            (Keys::new("bitwarden", &["s1", "s2"]), 1),
+            (Keys::new("age_file", &["s2"]), 1),
+        ])
+        .await
+        .unwrap_err();
+        assert!(format!("{report:?}").contains("age_file provided 0 distinct shares"));
+    }
+
+    #[tokio::test]
+    async fn every_source_has_to_meet_its_minimum() {
+        let report = combine(vec![
+            (Keys::new("bitwarden", &["s1", "s2"]), 1),
+            (Keys::new("age_file", &["s3"]), 2),
+        ])
+        .await
+        .unwrap_err();
+        assert!(format!("{report:?}").contains("age_file provided 1 distinct shares"));
+
+        let report = combine(vec![
+            (Keys::new("bitwarden", &["s1", "s2"]), 1),
+            (Keys::failing("age_file"), 1),
+        ])
+        .await
+        .unwrap_err();
+        assert!(format!("{report:?}").contains("age_file did not provide its shares"));
+    }
+}
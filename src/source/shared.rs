@@ -0,0 +1,237 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use error_stack::Report;
+use tokio::{sync::Mutex, time::Instant};
+use tracing::{Level, event};
+
+use crate::{
+    secret::Secret,
+    source::{self, KeySource},
+};
+
+enum Outcome {
+    Keys(Vec<Secret>, String),
+    // the report of the failed fetch, handed to the requests that waited
+    Failed(String),
+}
+
+struct Flight {
+    // number of the fetch, tells requests that waited for it apart from
+    // later ones even when the clock did not move
+    fetch: u64,
+    finished_at: Instant,
+    outcome: Outcome,
+}
+
+/// A key source shared by all node workers, concurrent fetches are
+/// coalesced into one and the keys may be kept for a short ttl
+pub struct SharedFetcher {
+    source: Arc<dyn KeySource>,
+    ttl: Duration,
+    last: Arc<Mutex<Option<Flight>>>,
+    fetches: AtomicU64,
+    requests: AtomicU64,
+}
+
+impl SharedFetcher {
+    pub fn new(source: Arc<dyn KeySource>, ttl: Duration) -> Self {
+        Self {
+            source,
+            ttl,
+            last: Arc::new(Mutex::new(None)),
+            fetches: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// number of fetches sent to the wrapped key source
+    pub fn fetches(&self) -> u64 {
+        self.fetches.load(Ordering::Relaxed)
+    }
+
+    /// number of fetches requested by the workers
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    // Drop the keys once the ttl is over, the mutex is fair so workers
+    // already waiting for the fetch still get them
+    fn expire(&self, fetch: u64) {
+        let last = self.last.clone();
+        let ttl = self.ttl;
+        tokio::spawn(async move {
+            tokio::time::sleep(ttl).await;
+            let mut last = last.lock().await;
+            if last.as_ref().is_some_and(|flight| flight.fetch == fetch) {
+                *last = None;
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl KeySource for SharedFetcher {
+    fn name(&self) -> &str {
+        self.source.name()
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        self.fetch_keys_from().await.map(|(keys, _)| keys)
+    }
+
+    async fn fetch_keys_from(&self) -> source::Result<(Vec<Secret>, String)> {
+        let seen = self.fetches();
+        self.requests.fetch_add(1, Ordering::Relaxed);
+
+        let mut last = self.last.lock().await;
+        if let Some(flight) = last.as_ref() {
+            // finished while this request waited, or still within the ttl
+            let joined = flight.fetch > seen;
+            match &flight.outcome {
+                Outcome::Keys(keys, from) if joined || flight.finished_at.elapsed() < self.ttl => {
+                    return Ok((keys.clone(), from.clone()));
+                }
+                Outcome::Failed(report) if joined => {
+                    return Err(Report::new(source::Error::FetchError).attach(format!(
+                        "the shared fetch from {} this request waited for failed: {report}",
+                        self.source.name()
+                    )));
+                }
+                _ => {}
+            }
+        }
+
+        let result = self.source.fetch_keys_from().await;
+        let fetch = self.fetches.fetch_add(1, Ordering::Relaxed) + 1;
+        event!(
+            Level::DEBUG,
+            "fetched keys from {}, {} fetches for {} requests",
+            self.source.name(),
+            self.fetches(),
+            self.requests()
+        );
+
+        *last = Some(Flight {
+            fetch,
+            finished_at: Instant::now(),
+            outcome: match &result {
+                Ok((keys, from)) => Outcome::Keys(keys.clone(), from.clone()),
+                Err(report) => Outcome::Failed(format!("{report:?}")),
+            },
+        });
+        self.expire(fetch);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+
+    use super::*;
+
+    const FETCH_TIME: Duration = Duration::from_millis(50);
+
+    /// Counts its fetches, each one takes a while so concurrent requests
+    /// pile up behind it
+    struct Counting {
+        fetches: AtomicU64,
+        fail: bool,
+    }
+
+    impl Counting {
+        fn new(fail: bool) -> Arc<Self> {
+            Arc::new(Self {
+                fetches: AtomicU64::new(0),
+                fail,
+            })
+        }
+    }
+
+    #[async_trait]
+    impl KeySource for Counting {
+        fn name(&self) -> &str {
+            "counting"
+        }
+
+        async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+            let fetch = self.fetches.fetch_add(1, Ordering::Relaxed) + 1;
+            tokio::time::sleep(FETCH_TIME).await;
+            if self.fail {
+                return Err(Report::new(source::Error::FetchError)
+                    .attach(format!("counting is down, fetch {fetch}")));
+            }
+            Ok(vec![Secret::new(format!("key-{fetch}"))])
+        }
+    }
+
+    async fn fetch_concurrently(fetcher: &SharedFetcher, n: usize) -> Vec<source::Result<String>> {
+        let requests = (0..n).map(|_| async {
+            let (keys, from) = fetcher.fetch_keys_from().await?;
+            assert_eq!(from, "counting");
+            Ok::<_, Report<source::Error>>(keys[0].expose().to_owned())
+        });
+        future::join_all(requests).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn concurrent_requests_share_one_fetch() {
+        let source = Counting::new(false);
+        let fetcher = SharedFetcher::new(source.clone(), Duration::ZERO);
+
+        let keys = fetch_concurrently(&fetcher, 8).await;
+        assert!(keys.iter().all(|key| key.as_deref().ok() == Some("key-1")));
+        assert_eq!(source.fetches.load(Ordering::Relaxed), 1);
+        assert_eq!((fetcher.fetches(), fetcher.requests()), (1, 8));
+
+        // without a ttl the next tick fetches again
+        let keys = fetch_concurrently(&fetcher, 1).await;
+        assert_eq!(keys[0].as_deref().ok(), Some("key-2"));
+        assert_eq!(fetcher.fetches(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keys_are_reused_until_the_ttl_expires() {
+        let ttl = Duration::from_secs(30);
+        let source = Counting::new(false);
+        let fetcher = SharedFetcher::new(source.clone(), ttl);
+
+        fetch_concurrently(&fetcher, 1).await;
+        tokio::time::advance(ttl - FETCH_TIME * 2).await;
+        let keys = fetch_concurrently(&fetcher, 1).await;
+        assert_eq!(keys[0].as_deref().ok(), Some("key-1"));
+        assert_eq!(fetcher.fetches(), 1);
+
+        tokio::time::advance(FETCH_TIME * 2).await;
+        let keys = fetch_concurrently(&fetcher, 1).await;
+        assert_eq!(keys[0].as_deref().ok(), Some("key-2"));
+        assert_eq!(fetcher.fetches(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_fetch_fails_every_waiting_request() {
+        let source = Counting::new(true);
+        let fetcher = SharedFetcher::new(source.clone(), Duration::from_secs(60));
+
+        let keys = fetch_concurrently(&fetcher, 4).await;
+        assert_eq!(source.fetches.load(Ordering::Relaxed), 1);
+        for report in keys.iter().map(|key| key.as_ref().unwrap_err()) {
+            // waiting requests get the cause, not just the failure
+            assert!(format!("{report:?}").contains("counting is down, fetch 1"));
+        }
+
+        // a failure is not kept for the ttl, the next request retries
+        let keys = fetch_concurrently(&fetcher, 1).await;
+        let report = keys[0].as_ref().unwrap_err();
+        assert!(format!("{report:?}").contains("counting is down, fetch 2"));
+        assert_eq!(fetcher.fetches(), 2);
+    }
+}
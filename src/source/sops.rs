@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use thiserror::Error;
+use tokio::process::Command;
+use tracing::instrument;
+use zeroize::Zeroizing;
+
+use crate::{
+    conf,
+    secret::Secret,
+    source::{self, JsonDocument, KeySource, process},
+};
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("sops spawn error")]
+    SpawnError,
+    #[error("sops timed out")]
+    Timeout,
+    #[error("sops decrypt error")]
+    DecryptError,
+}
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// A decrypted document larger than this is not a key file
+const MAX_OUTPUT_BYTES: u64 = 1024 * 1024;
+
+/// Unseal keys stored in a sops encrypted document, decrypted by the `sops`
+/// binary so every format, key type and mac rule of sops is supported
+pub struct SopsFile {
+    cfg: conf::Sops,
+}
+
+impl SopsFile {
+    pub fn new(cfg: &conf::Sops) -> Self {
+        Self { cfg: cfg.clone() }
+    }
+
+    #[instrument(name = "sops::decrypt", skip(self), fields(path = %self.cfg.path.display()))]
+    async fn decrypt(&self) -> Result<Zeroizing<Vec<u8>>> {
+        let mut command = Command::new(&self.cfg.binary);
+        command
+            .args(["--decrypt", "--output-type", "json"])
+            .arg(&self.cfg.path);
+        if let Some(identity_file) = &self.cfg.age_identity_file {
+            command.env("SOPS_AGE_KEY_FILE", identity_file);
+        }
+        if let Some(home) = &self.cfg.gnupg_home {
+            command.env("GNUPGHOME", home);
+        }
+
+        let timeout = Duration::from_secs(self.cfg.timeout);
+        process::run(command, timeout, MAX_OUTPUT_BYTES)
+            .await
+            .map_err(|report| {
+                let context = match report.current_context() {
+                    process::Error::SpawnError => Error::SpawnError,
+                    process::Error::Timeout => Error::Timeout,
+                    process::Error::ExitError | process::Error::OutputTooLarge => {
+                        Error::DecryptError
+                    }
+                };
+                report
+                    .change_context(context)
+                    .attach(format!("failed to decrypt {}", self.cfg.path.display()))
+            })
+    }
+}
+
+#[async_trait]
+impl KeySource for SopsFile {
+    fn name(&self) -> &str {
+        "sops"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        let stdout = self
+            .decrypt()
+            .await
+            .change_context(source::Error::FetchError)?;
+        let mut document = JsonDocument(
+            serde_json::from_slice(&stdout)
+                .change_context(source::Error::FetchError)
+                .attach("sops printed invalid json")?,
+        );
+
+        document
+            .take_keys(&self.cfg.key_path)
+            .attach(format!("invalid keys in {}", self.cfg.path.display()))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, path::PathBuf};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    // A stand-in for sops, the script runs instead of the real binary as
+    // long as the directory is kept
+    fn sops(script: &str) -> (TempDir, conf::Sops) {
+        let dir = TempDir::new().unwrap();
+        let binary = dir.path().join("sops");
+        fs::write(&binary, format!("#!/bin/sh\n{script}\n")).unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let cfg = conf::Sops {
+            path: PathBuf::from("/etc/vault-unseal/secrets.enc.yaml"),
+            key_path: "/vault/unseal_keys".to_owned(),
+            age_identity_file: Some(PathBuf::from("/etc/vault-unseal/identity.txt")),
+            gnupg_home: None,
+            binary,
+            timeout: 5,
+        };
+        (dir, cfg)
+    }
+
+    fn exposed(keys: &[Secret]) -> Vec<&str> {
+        keys.iter().map(Secret::expose).collect()
+    }
+
+    #[tokio::test]
+    async fn keys_are_read_from_the_decrypted_document() {
+        let (_dir, cfg) =
+            sops(r#"printf '{"vault":{"unseal_keys":["%s","%s"]}}' "$*" "$SOPS_AGE_KEY_FILE""#);
+
+        let keys = SopsFile::new(&cfg).fetch_keys().await.unwrap();
+        assert_eq!(
+            exposed(&keys),
+            [
+                "--decrypt --output-type json /etc/vault-unseal/secrets.enc.yaml",
+                "/etc/vault-unseal/identity.txt"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn newline_joined_keys_are_split() {
+        let (_dir, cfg) = sops(r#"printf '{"vault":{"unseal_keys":"key-1\\nkey-2\\n"}}'"#);
+
+        let keys = SopsFile::new(&cfg).fetch_keys().await.unwrap();
+        assert_eq!(exposed(&keys), ["key-1", "key-2"]);
+    }
+
+    #[tokio::test]
+    async fn sops_failure_is_an_error() {
+        let (_dir, cfg) = sops("echo 'MAC mismatch' >&2; exit 128");
+
+        let report = SopsFile::new(&cfg).decrypt().await.unwrap_err();
+        assert!(matches!(report.current_context(), Error::DecryptError));
+    }
+
+    #[tokio::test]
+    async fn sops_is_killed_after_the_timeout() {
+        let (_dir, mut cfg) = sops("sleep 10");
+        cfg.timeout = 1;
+
+        let report = SopsFile::new(&cfg).decrypt().await.unwrap_err();
+        assert!(matches!(report.current_context(), Error::Timeout));
+    }
+}
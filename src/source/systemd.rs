@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+use async_trait::async_trait;
+use error_stack::{Report, ResultExt};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use crate::{
+    conf,
+    secret::Secret,
+    source::{self, KeySource},
+};
+
+#[derive(Error, Debug)]
+#[error("systemd credential error")]
+pub struct Error;
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Unseal keys passed in by systemd with `LoadCredential=` or
+/// `LoadCredentialEncrypted=`, each credential holds one or more keys
+pub struct SystemdCredentials {
+    names: Vec<String>,
+}
+
+impl SystemdCredentials {
+    pub fn new(cfg: &conf::SystemdCredentials) -> Self {
+        Self {
+            names: cfg.names.clone(),
+        }
+    }
+
+    fn read(&self) -> Result<Vec<Secret>> {
+        // systemd only sets this for the service the credentials belong to
+        let dir = std::env::var_os("CREDENTIALS_DIRECTORY")
+            .map(PathBuf::from)
+            .ok_or_else(|| {
+                Report::new(Error).attach(
+                    "CREDENTIALS_DIRECTORY is not set, run as a systemd service with LoadCredential",
+                )
+            })?;
+
+        let mut keys = Vec::new();
+        for name in &self.names {
+            let path = dir.join(name);
+            let content = Zeroizing::new(
+                std::fs::read_to_string(&path)
+                    .change_context(Error)
+                    .attach(format!("failed to read credential {}", path.display()))?,
+            );
+            keys.extend(
+                source::parse_keys(&content)
+                    .change_context(Error)
+                    .attach(format!("invalid credential {name}"))?,
+            );
+        }
+
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl KeySource for SystemdCredentials {
+    fn name(&self) -> &str {
+        "systemd"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        self.read().change_context(source::Error::FetchError)
+    }
+}
@@ -0,0 +1,447 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use error_stack::{Report, ResultExt};
+use rustify::clients::reqwest::Client as HTTPClient;
+use rustify_derive::Endpoint;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::sync::Mutex;
+use url::Url;
+use vaultrs::{
+    api,
+    auth::{approle, cert},
+    client::{VaultClient, VaultClientSettingsBuilder},
+    kv2,
+};
+use zeroize::Zeroizing;
+
+use crate::{
+    conf::{self, VaultAuth, VaultKeys},
+    secret::Secret,
+    source::{self, KeySource},
+    tls,
+};
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("key vault client error")]
+    ClientError,
+    #[error("key vault login error")]
+    LoginError,
+    #[error("key vault read error")]
+    ReadError,
+}
+
+type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// A login token is renewed this long before its lease ends
+const TOKEN_RENEW_MARGIN: Duration = Duration::from_secs(30);
+
+// vaultrs' transit response keeps the plaintext in a plain string
+#[derive(Debug, Endpoint)]
+#[endpoint(
+    path = "{self.mount}/decrypt/{self.key}",
+    method = "POST",
+    response = "TransitDecryptResponse"
+)]
+struct TransitDecryptRequest {
+    #[endpoint(skip)]
+    mount: String,
+    #[endpoint(skip)]
+    key: String,
+    ciphertext: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TransitDecryptResponse {
+    /// base64 of the unseal key
+    plaintext: Secret,
+}
+
+/// The client with the token of the last login
+struct Session {
+    client: VaultClient,
+    logged_in: bool,
+    /// when the token has to be replaced, `None` if it does not expire
+    renew_at: Option<Instant>,
+}
+
+impl Session {
+    fn needs_login(&self) -> bool {
+        !self.logged_in || self.renew_at.is_some_and(|at| Instant::now() >= at)
+    }
+}
+
+/// Unseal keys kept by a second vault, read from kv v2 or decrypted with
+/// its transit engine
+pub struct VaultSecret {
+    session: Mutex<Session>,
+    address: Url,
+    auth: VaultAuth,
+    keys: VaultKeys,
+}
+
+impl VaultSecret {
+    pub fn new(cfg: &conf::VaultSource) -> Result<Self> {
+        let (address, http) =
+            tls::http_client(&cfg.address, &cfg.tls, &[]).change_context(Error::ClientError)?;
+        let mut client = VaultClient::new(
+            VaultClientSettingsBuilder::default()
+                .address(&address)
+                .build()
+                .change_context(Error::ClientError)?,
+        )
+        .change_context(Error::ClientError)?;
+        // same as the workers, the client certificate is needed for cert auth
+        client.http = HTTPClient::new(address.as_str(), http);
+
+        Ok(Self {
+            session: Mutex::new(Session {
+                client,
+                logged_in: false,
+                renew_at: None,
+            }),
+            address: cfg.address.clone(),
+            auth: cfg.auth.clone(),
+            keys: cfg.keys.clone(),
+        })
+    }
+
+    // Login again only once the token is about to expire, every approle or
+    // cert login creates a new token
+    async fn login(&self, session: &mut Session) -> Result<()> {
+        if !session.needs_login() {
+            return Ok(());
+        }
+
+        let client = &mut session.client;
+        let auth = match &self.auth {
+            VaultAuth::Token(token) => {
+                client.set_token(token.expose());
+                session.logged_in = true;
+                return Ok(());
+            }
+            VaultAuth::AppRole {
+                mount,
+                role_id,
+                secret_id,
+            } => approle::login(&*client, mount, role_id, secret_id.expose())
+                .await
+                .change_context(Error::LoginError)
+                .attach(format!("approle login at {} failed", self.address))?,
+            VaultAuth::Cert { mount, role } => {
+                cert::login(&*client, mount, role.as_deref().unwrap_or_default())
+                    .await
+                    .change_context(Error::LoginError)
+                    .attach(format!("cert login at {} failed", self.address))?
+            }
+        };
+
+        let token = Zeroizing::new(auth.client_token);
+        client.set_token(&token);
+        session.logged_in = true;
+        session.renew_at = (auth.lease_duration > 0).then(|| {
+            Instant::now()
+                + Duration::from_secs(auth.lease_duration).saturating_sub(TOKEN_RENEW_MARGIN)
+        });
+        Ok(())
+    }
+
+    async fn read_keys(&self, client: &VaultClient) -> Result<Vec<Secret>> {
+        match &self.keys {
+            VaultKeys::Kv {
+                mount,
+                path,
+                fields,
+            } => {
+                let mut data: HashMap<String, Secret> = kv2::read(client, mount, path)
+                    .await
+                    .change_context(Error::ReadError)
+                    .attach(format!(
+                        "failed to read {mount}/{path} from {}",
+                        self.address
+                    ))?;
+
+                if fields.is_empty() {
+                    let mut data: Vec<_> = data.into_iter().collect();
+                    data.sort_by(|(a, _), (b, _)| a.cmp(b));
+                    return Ok(data.into_iter().map(|(_, key)| key).collect());
+                }
+
+                fields
+                    .iter()
+                    .map(|field| {
+                        data.remove(field).ok_or_else(|| {
+                            Report::new(Error::ReadError)
+                                .attach(format!("{mount}/{path} has no field {field}"))
+                        })
+                    })
+                    .collect()
+            }
+            VaultKeys::Transit {
+                mount,
+                key,
+                ciphertexts,
+            } => {
+                let mut keys = Vec::with_capacity(ciphertexts.len());
+                for ciphertext in ciphertexts {
+                    let endpoint = TransitDecryptRequest {
+                        mount: mount.clone(),
+                        key: key.clone(),
+                        ciphertext: ciphertext.clone(),
+                    };
+                    let response = api::exec_with_result(client, endpoint)
+                        .await
+                        .change_context(Error::ReadError)
+                        .attach(format!(
+                            "failed to decrypt with {mount}/keys/{key} at {}",
+                            self.address
+                        ))?;
+
+                    let plaintext = Zeroizing::new(
+                        BASE64
+                            .decode(response.plaintext.expose())
+                            .change_context(Error::ReadError)
+                            .attach("transit plaintext is not base64")?,
+                    );
+                    let plaintext = String::from_utf8(plaintext.to_vec())
+                        .change_context(Error::ReadError)
+                        .attach("transit plaintext is not utf-8")?;
+                    keys.push(Secret::new(plaintext));
+                }
+                Ok(keys)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl KeySource for VaultSecret {
+    fn name(&self) -> &str {
+        "vault"
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        let mut session = self.session.lock().await;
+        self.login(&mut session)
+            .await
+            .change_context(source::Error::FetchError)?;
+
+        let keys = self.read_keys(&session.client).await;
+        // the token may have been revoked, log in again on the next fetch
+        if keys.is_err() {
+            session.logged_in = false;
+        }
+        keys.change_context(source::Error::FetchError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::{
+        conf::Tls,
+        testing::{self, Request, Stub},
+    };
+
+    const TOKEN: &str = "hvs.CAESIJ2kQ5vYmC6Q1rUmcA2LhN3vZ";
+
+    // A vault answer with the envelope every endpoint uses
+    fn envelope(data: Value, auth: Value) -> Vec<u8> {
+        let body = json!({
+            "request_id": "5bc3e5a0-2f2c-7f1b-8b5e-0c1d9f2a4b6e",
+            "lease_id": "",
+            "renewable": false,
+            "lease_duration": 0,
+            "data": data,
+            "wrap_info": null,
+            "warnings": null,
+            "auth": auth,
+        });
+        testing::json(200, &body.to_string())
+    }
+
+    fn login(lease_duration: u64) -> Vec<u8> {
+        let auth = json!({
+            "client_token": TOKEN,
+            "accessor": "0e9e354a-520f-df04-6867-ee81cae3d42d",
+            "policies": ["default", "vault-unseal"],
+            "token_policies": ["default", "vault-unseal"],
+            "metadata": { "role_name": "vault-unseal" },
+            "lease_duration": lease_duration,
+            "renewable": true,
+            "entity_id": "",
+            "token_type": "service",
+            "orphan": true,
+            "mfa_requirement": null,
+            "num_uses": 0,
+        });
+        envelope(Value::Null, auth)
+    }
+
+    fn kv(data: Value) -> Vec<u8> {
+        let data = json!({
+            "data": data,
+            "metadata": {
+                "created_time": "2025-01-01T00:00:00.000000000Z",
+                "custom_metadata": null,
+                "deletion_time": "",
+                "destroyed": false,
+                "version": 1,
+            },
+        });
+        envelope(data, Value::Null)
+    }
+
+    fn body(request: &Request) -> Value {
+        serde_json::from_slice(&request.body).unwrap()
+    }
+
+    fn approle(stub: &Stub, keys: VaultKeys) -> VaultSecret {
+        let cfg = conf::VaultSource {
+            address: stub.url.clone(),
+            tls: Tls::default(),
+            auth: VaultAuth::AppRole {
+                mount: "approle".to_owned(),
+                role_id: "vault-unseal".to_owned(),
+                secret_id: Secret::new("6a174c20-f6de-a53c-74d2-6018fcceff64".to_owned()),
+            },
+            keys,
+        };
+        VaultSecret::new(&cfg).unwrap()
+    }
+
+    fn kv_keys(fields: &[&str]) -> VaultKeys {
+        VaultKeys::Kv {
+            mount: "secret".to_owned(),
+            path: "vault/unseal".to_owned(),
+            fields: fields.iter().map(|field| (*field).to_owned()).collect(),
+        }
+    }
+
+    async fn fetch(source: &VaultSecret) -> source::Result<Vec<String>> {
+        let keys = source.fetch_keys().await?;
+        Ok(keys.iter().map(|key| key.expose().to_owned()).collect())
+    }
+
+    fn kv_vault(lease_duration: u64) -> Stub {
+        Stub::serve(move |request| match request.path.as_str() {
+            "/v1/auth/approle/login" => login(lease_duration),
+            _ => kv(json!({ "key2": "k2", "key1": "k1", "key3": "k3" })),
+        })
+    }
+
+    #[tokio::test]
+    async fn approle_token_is_reused_until_it_expires() {
+        let stub = kv_vault(3600);
+        let source = approle(&stub, kv_keys(&[]));
+        assert_eq!(fetch(&source).await.unwrap(), ["k1", "k2", "k3"]);
+        assert_eq!(fetch(&source).await.unwrap(), ["k1", "k2", "k3"]);
+
+        assert_eq!(
+            stub.calls(),
+            [
+                "POST /v1/auth/approle/login",
+                "GET /v1/secret/data/vault/unseal",
+                "GET /v1/secret/data/vault/unseal",
+            ]
+        );
+        let requests = stub.requests();
+        let credentials = body(&requests[0]);
+        assert_eq!(credentials["role_id"], "vault-unseal");
+        assert_eq!(
+            credentials["secret_id"],
+            "6a174c20-f6de-a53c-74d2-6018fcceff64"
+        );
+        for read in &requests[1..] {
+            assert_eq!(read.header("x-vault-token"), Some(TOKEN));
+        }
+    }
+
+    #[tokio::test]
+    async fn token_about_to_expire_is_replaced() {
+        let stub = kv_vault(10);
+        let source = approle(&stub, kv_keys(&["key3", "key1"]));
+        assert_eq!(fetch(&source).await.unwrap(), ["k3", "k1"]);
+        assert_eq!(fetch(&source).await.unwrap(), ["k3", "k1"]);
+
+        assert_eq!(
+            stub.calls(),
+            [
+                "POST /v1/auth/approle/login",
+                "GET /v1/secret/data/vault/unseal",
+                "POST /v1/auth/approle/login",
+                "GET /v1/secret/data/vault/unseal",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_read_logs_in_again() {
+        let stub = Stub::serve(|request| match request.path.as_str() {
+            "/v1/auth/approle/login" => login(3600),
+            _ => testing::json(403, r#"{"errors":["permission denied"]}"#),
+        });
+        let source = approle(&stub, kv_keys(&[]));
+        assert!(fetch(&source).await.is_err());
+        assert!(fetch(&source).await.is_err());
+
+        let logins = stub
+            .calls()
+            .iter()
+            .filter(|call| *call == "POST /v1/auth/approle/login")
+            .count();
+        assert_eq!(logins, 2);
+    }
+
+    #[tokio::test]
+    async fn missing_kv_field_is_rejected() {
+        let stub = kv_vault(3600);
+        let source = approle(&stub, kv_keys(&["key1", "key4"]));
+        assert!(fetch(&source).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn transit_ciphertexts_are_decrypted() {
+        let stub = Stub::serve(|request| match request.path.as_str() {
+            "/v1/auth/approle/login" => login(3600),
+            _ => {
+                let plaintext = match body(request)["ciphertext"].as_str() {
+                    Some("vault:v1:c2hhcmUtMQ") => BASE64.encode("share-1"),
+                    _ => BASE64.encode("share-2"),
+                };
+                envelope(json!({ "plaintext": plaintext }), Value::Null)
+            }
+        });
+        let keys = VaultKeys::Transit {
+            mount: "transit".to_owned(),
+            key: "unseal".to_owned(),
+            ciphertexts: vec![
+                "vault:v1:c2hhcmUtMQ".to_owned(),
+                "vault:v1:c2hhcmUtMg".to_owned(),
+            ],
+        };
+        let source = approle(&stub, keys);
+        assert_eq!(fetch(&source).await.unwrap(), ["share-1", "share-2"]);
+
+        assert_eq!(
+            stub.calls(),
+            [
+                "POST /v1/auth/approle/login",
+                "POST /v1/transit/decrypt/unseal",
+                "POST /v1/transit/decrypt/unseal",
+            ]
+        );
+        let requests = stub.requests();
+        assert_eq!(body(&requests[1])["ciphertext"], "vault:v1:c2hhcmUtMQ");
+        assert_eq!(requests[2].header("x-vault-token"), Some(TOKEN));
+    }
+}
@@ -0,0 +1,194 @@
+//! Local http stub for tests, one thread per connection and every response
+//! closes the connection so each request is seen on its own, and an
+//! in-memory key source
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+};
+
+use async_trait::async_trait;
+use error_stack::Report;
+use url::Url;
+
+use crate::{
+    secret::Secret,
+    source::{self, KeySource},
+};
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Stub {
+    pub url: Url,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Stub {
+    /// Serve every request with the raw http response returned by `respond`
+    pub fn serve(respond: impl Fn(&Request) -> Vec<u8> + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let respond = Arc::new(respond);
+        let seen = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let respond = respond.clone();
+                let seen = seen.clone();
+                thread::spawn(move || handle(stream, respond.as_ref(), &seen));
+            }
+        });
+
+        Self { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// `METHOD /path` of every request in order
+    pub fn calls(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .map(|request| format!("{} {}", request.method, request.path))
+            .collect()
+    }
+}
+
+fn handle(
+    stream: TcpStream,
+    respond: &(dyn Fn(&Request) -> Vec<u8> + Send + Sync),
+    seen: &Mutex<Vec<Request>>,
+) {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    if reader.read_line(&mut line).unwrap_or(0) == 0 {
+        return;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let path = parts.next().unwrap_or_default().to_owned();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((key, value)) = line.split_once(':') {
+            headers.push((key.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+    let length = request
+        .header("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    request.body.resize(length, 0);
+    reader.read_exact(&mut request.body).unwrap();
+
+    seen.lock().unwrap().push(request.clone());
+    let response = respond(&request);
+    // the client may already have given up, e.g. in timeout tests
+    let _ = reader.get_mut().write_all(&response);
+}
+
+/// A complete response with a content length
+pub fn response(status: u16, content_type: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {status} Stub\r\ncontent-type: {content_type}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+        body.len()
+    )
+    .into_bytes()
+}
+
+pub fn json(status: u16, body: &str) -> Vec<u8> {
+    response(status, "application/json", body)
+}
+
+/// A chunked response without a content length
+pub fn chunked(status: u16, chunks: &[&str]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {status} Stub\r\ncontent-type: application/json\r\ntransfer-encoding: chunked\r\nconnection: close\r\n\r\n"
+    );
+    for chunk in chunks {
+        response.push_str(&format!("{:x}\r\n{chunk}\r\n", chunk.len()));
+    }
+    response.push_str("0\r\n\r\n");
+    response.into_bytes()
+}
+
+/// A key source answering from memory, `None` keys make every fetch fail
+pub struct Keys {
+    name: String,
+    keys: Option<Vec<String>>,
+    fetches: AtomicUsize,
+}
+
+impl Keys {
+    pub fn new(name: &str, keys: &[&str]) -> Self {
+        Self {
+            name: name.to_owned(),
+            keys: Some(keys.iter().map(|key| (*key).to_owned()).collect()),
+            fetches: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn failing(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            keys: None,
+            fetches: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn fetches(&self) -> usize {
+        self.fetches.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait]
+impl KeySource for Keys {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn fetch_keys(&self) -> source::Result<Vec<Secret>> {
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+        match &self.keys {
+            Some(keys) => Ok(keys.iter().cloned().map(Secret::new).collect()),
+            None => Err(Report::new(source::Error::FetchError)
+                .attach(format!("{} is unavailable", self.name))),
+        }
+    }
+}
@@ -0,0 +1,483 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Arc,
+};
+
+use aws_lc_rs::digest;
+use error_stack::{Report, ResultExt};
+use reqwest::redirect;
+use rustls::{
+    ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    client::{
+        WebPkiServerVerifier,
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    },
+    crypto::{self, CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+};
+use thiserror::Error;
+use tracing::{Level, event};
+use url::{Host, Url};
+use webpki::EndEntityCert;
+
+use crate::conf::{CertPin, Tls};
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("tls certificate error")]
+    CertificateError,
+    #[error("tls config error")]
+    ConfigError,
+    #[error("http client error")]
+    ClientError,
+}
+
+pub type Result<T> = std::result::Result<T, Report<Error>>;
+
+/// Redirects followed before giving up, same as the reqwest default
+const MAX_REDIRECTS: usize = 10;
+
+/// Build the http client used to talk to `host`, returns the address the
+/// client has to be pointed at, which differs from `host` when the server
+/// name is overridden.
+pub fn http_client(host: &Url, tls: &Tls, pins: &[CertPin]) -> Result<(Url, reqwest::Client)> {
+    let mut address = host.clone();
+    let mut builder = reqwest::Client::builder()
+        .use_preconfigured_tls(client_config(tls, pins)?)
+        .redirect(redirect_policy());
+
+    // rustls takes the server name from the url, so connect by name and
+    // resolve that name to the configured ip instead
+    if let Some(server_name) = &tls.server_name {
+        let ip: IpAddr = match host.host() {
+            Some(Host::Ipv4(ip)) => ip.into(),
+            Some(Host::Ipv6(ip)) => ip.into(),
+            _ => {
+                let report = Report::new(Error::ConfigError).attach(format!(
+                    "tls server name override requires an ip address, got {host}"
+                ));
+                return Err(report);
+            }
+        };
+        let port = host.port_or_known_default().unwrap_or(443);
+
+        address
+            .set_host(Some(server_name))
+            .change_context(Error::ConfigError)
+            .attach(format!("invalid tls server name: {server_name}"))?;
+        builder = builder.resolve(server_name, SocketAddr::new(ip, port));
+    }
+
+    let client = builder.build().change_context(Error::ClientError)?;
+    Ok((address, client))
+}
+
+// Follow redirects like reqwest does, but never from https to plaintext
+// http, requests carry unseal keys, tokens or credentials
+fn redirect_policy() -> redirect::Policy {
+    redirect::Policy::custom(
+        |attempt| match check_redirect(attempt.previous(), attempt.url()) {
+            Ok(()) => attempt.follow(),
+            Err(reason) => attempt.error(reason),
+        },
+    )
+}
+
+fn check_redirect(previous: &[Url], next: &Url) -> std::result::Result<(), String> {
+    if previous.len() > MAX_REDIRECTS {
+        return Err(format!("more than {MAX_REDIRECTS} redirects"));
+    }
+    if next.scheme() != "https" && previous.iter().any(|url| url.scheme() == "https") {
+        return Err(format!(
+            "refusing to follow a redirect from https to {next}"
+        ));
+    }
+    Ok(())
+}
+
+/// Build a rustls client config from the tls settings, when pins are given
+/// the leaf certificate has to match one of them on top of the chain checks
+pub fn client_config(tls: &Tls, pins: &[CertPin]) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .change_context(Error::ClientError)?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier(tls, pins)?);
+
+    let config = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            let certs = load_certs(cert)?;
+            let key = PrivateKeyDer::from_pem_file(key)
+                .change_context(Error::CertificateError)
+                .attach(format!("failed to read client key from {}", key.display()))?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .change_context(Error::CertificateError)
+                .attach("invalid client certificate or key")?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+
+    Ok(config)
+}
+
+fn verifier(tls: &Tls, pins: &[CertPin]) -> Result<Arc<dyn ServerCertVerifier>> {
+    let verifier: Arc<dyn ServerCertVerifier> = if tls.insecure_skip_verify.unwrap_or(false) {
+        if pins.is_empty() {
+            event!(
+                Level::WARN,
+                "tls certificate verification is disabled, unseal keys can be intercepted"
+            );
+        } else {
+            event!(
+                Level::WARN,
+                "tls chain verification is disabled, relying on certificate pins only"
+            );
+        }
+        Arc::new(NoVerification::new())
+    } else {
+        WebPkiServerVerifier::builder_with_provider(Arc::new(root_store(tls)?), provider())
+            .build()
+            .change_context(Error::ConfigError)?
+    };
+
+    if pins.is_empty() {
+        return Ok(verifier);
+    }
+    Ok(Arc::new(PinnedVerifier {
+        inner: verifier,
+        pins: pins.to_vec(),
+    }))
+}
+
+// Trust the configured ca bundle and directory, or the system roots if
+// neither is set
+fn root_store(tls: &Tls) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+
+    if tls.ca_file.is_none() && tls.ca_dir.is_none() {
+        let native = rustls_native_certs::load_native_certs();
+        for error in native.errors {
+            event!(Level::WARN, "failed to load system certificate: {error}");
+        }
+        roots.add_parsable_certificates(native.certs);
+        return Ok(roots);
+    }
+
+    let mut certs = Vec::new();
+    if let Some(ca_file) = &tls.ca_file {
+        certs.extend(load_certs(ca_file)?);
+    }
+    if let Some(ca_dir) = &tls.ca_dir {
+        let entries = std::fs::read_dir(ca_dir)
+            .change_context(Error::CertificateError)
+            .attach(format!("failed to read ca dir {}", ca_dir.display()))?;
+        for entry in entries {
+            let path = entry.change_context(Error::CertificateError)?.path();
+            if path.is_file() {
+                certs.extend(load_certs(&path)?);
+            }
+        }
+    }
+
+    for cert in certs {
+        roots.add(cert).change_context(Error::CertificateError)?;
+    }
+
+    if roots.is_empty() {
+        let report = Report::new(Error::CertificateError)
+            .attach("no ca certificates found in the configured ca file or dir");
+        return Err(report);
+    }
+
+    Ok(roots)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    CertificateDer::pem_file_iter(path)
+        .change_context(Error::CertificateError)
+        .attach(format!("failed to open {}", path.display()))?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .change_context(Error::CertificateError)
+        .attach(format!(
+            "failed to parse certificates in {}",
+            path.display()
+        ))
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let hash = digest::digest(&digest::SHA256, data);
+    hash.as_ref().try_into().unwrap()
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    CryptoProvider::get_default()
+        .cloned()
+        .unwrap_or_else(|| Arc::new(crypto::aws_lc_rs::default_provider()))
+}
+
+/// Accepts any server certificate, handshake signatures are still checked
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl NoVerification {
+    fn new() -> Self {
+        Self(provider())
+    }
+}
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Checks the leaf certificate against the configured pins after the inner
+/// verifier accepted the chain, so a rogue ca alone cannot redirect keys
+#[derive(Debug)]
+struct PinnedVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Vec<CertPin>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let spki = EndEntityCert::try_from(end_entity)
+            .map_err(|e| rustls::Error::General(format!("failed to parse certificate: {e}")))?
+            .subject_public_key_info();
+        let observed_spki = CertPin::Spki(sha256(spki.as_ref()));
+        let observed_cert = CertPin::Cert(sha256(end_entity.as_ref()));
+
+        if self
+            .pins
+            .iter()
+            .any(|pin| *pin == observed_spki || *pin == observed_cert)
+        {
+            return Ok(verified);
+        }
+
+        let message = format!(
+            "certificate of {} does not match any pin, observed {} and {}",
+            server_name.to_str(),
+            observed_spki,
+            observed_cert
+        );
+        event!(Level::ERROR, "{message}");
+        Err(rustls::Error::General(message))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a test ca and a leaf for vault.example.com it signed, the spki pin
+    // was taken with openssl x509 -pubkey -noout | openssl pkey -pubin
+    // -outform der | openssl dgst -sha256 -binary | base64
+    const CA: &str = "-----BEGIN CERTIFICATE-----
+MIIBlTCCATugAwIBAgIUMlJEy7BWPqAOdxfiKBGva6pzd9MwCgYIKoZIzj0EAwIw
+HzEdMBsGA1UEAwwUdmF1bHQtdW5zZWFsIHRlc3QgY2EwIBcNMjYxMDE2MjA1MDU0
+WhgPMjEyNjA5MjIyMDUwNTRaMB8xHTAbBgNVBAMMFHZhdWx0LXVuc2VhbCB0ZXN0
+IGNhMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEvmMTiGBNeis2J45/MYLBwbzn
+VmhnE3dC9R4O91pBrKNIiu01ABkT6h1My392D8vKl0sVxa+dXlwTgoLHTwFdaqNT
+MFEwHQYDVR0OBBYEFJ0gD5mUryzuoiangIC562fXQYpsMB8GA1UdIwQYMBaAFJ0g
+D5mUryzuoiangIC562fXQYpsMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwID
+SAAwRQIhAPAM24PC/2YMBcLEKUtr+slbFoJ+/3Lwpkt0xSWrbzFdAiBq5+k65phF
+RrRrV0iCWKZeOqYZaC1A9WnoJpUuTu43zQ==
+-----END CERTIFICATE-----
+";
+    const LEAF: &str = "-----BEGIN CERTIFICATE-----
+MIIBzjCCAXSgAwIBAgIUMT2//ZEbOwiB8wTDKxFTUucALaQwCgYIKoZIzj0EAwIw
+HzEdMBsGA1UEAwwUdmF1bHQtdW5zZWFsIHRlc3QgY2EwIBcNMjYxMDE2MjA1MDU0
+WhgPMjEyNjA5MjIyMDUwNTRaMBwxGjAYBgNVBAMMEXZhdWx0LmV4YW1wbGUuY29t
+MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEBr7vtgWyWlPi9C1GgZn7WTwj8Y90
++jOV6UBw65DEkO/W95Z4hHrS0n18tXpl1Ta8D0/4RXP7jkaFUU8iclxC/6OBjjCB
+izAcBgNVHREEFTATghF2YXVsdC5leGFtcGxlLmNvbTAJBgNVHRMEAjAAMAsGA1Ud
+DwQEAwIHgDATBgNVHSUEDDAKBggrBgEFBQcDATAdBgNVHQ4EFgQULXkuCe12ROug
+Q0gaP3kqdDaQJIgwHwYDVR0jBBgwFoAUnSAPmZSvLO6iJqeAgLnrZ9dBimwwCgYI
+KoZIzj0EAwIDSAAwRQIgYuVTRtsWSIQqeDf+/2cJp3w0n7b4isG4V+Fp17gqPfYC
+IQDaiTmOHVVcGS46msP7ptQf0NnX0PwZVJCH4tiKH54+7g==
+-----END CERTIFICATE-----
+";
+    const PIN: &str = "spki-sha256:7rBVzGdLE7Wiv3ZLteVntGhZtmaIum9GN7oqxMi3hT0=";
+    const WRONG_PIN: &str = "spki-sha256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+
+    // trusts only the given roots, the directory has to outlive the check
+    fn trusting(roots: &str) -> (tempfile::TempDir, Tls) {
+        let dir = tempfile::TempDir::new().unwrap();
+        let ca_file = dir.path().join("ca.pem");
+        std::fs::write(&ca_file, roots).unwrap();
+        let tls = Tls {
+            ca_file: Some(ca_file),
+            ..Tls::default()
+        };
+        (dir, tls)
+    }
+
+    fn skipping_verification() -> Tls {
+        Tls {
+            insecure_skip_verify: Some(true),
+            ..Tls::default()
+        }
+    }
+
+    fn verify(tls: &Tls, pin: &str) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let leaf = CertificateDer::from_pem_slice(LEAF.as_bytes()).unwrap();
+        let server_name = ServerName::try_from("vault.example.com").unwrap();
+        verifier(tls, &[pin.parse().unwrap()])
+            .unwrap()
+            .verify_server_cert(&leaf, &[], &server_name, &[], UnixTime::now())
+    }
+
+    #[test]
+    fn matching_spki_pin_is_accepted() {
+        let (_dir, tls) = trusting(CA);
+        assert!(verify(&tls, PIN).is_ok());
+    }
+
+    #[test]
+    fn wrong_pin_is_rejected() {
+        let (_dir, tls) = trusting(CA);
+        let err = verify(&tls, WRONG_PIN).unwrap_err();
+        assert!(err.to_string().contains("does not match any pin"));
+    }
+
+    #[test]
+    fn pins_are_enforced_when_chain_verification_is_skipped() {
+        assert!(verify(&skipping_verification(), PIN).is_ok());
+        let err = verify(&skipping_verification(), WRONG_PIN).unwrap_err();
+        assert!(err.to_string().contains("does not match any pin"));
+    }
+
+    #[test]
+    fn matching_pin_does_not_replace_chain_verification() {
+        // the leaf is not signed by the only trusted root
+        let (_dir, tls) = trusting(LEAF);
+        let err = verify(&tls, PIN).unwrap_err();
+        assert!(!err.to_string().contains("pin"));
+    }
+
+    fn urls(urls: &[&str]) -> Vec<Url> {
+        urls.iter().map(|url| Url::parse(url).unwrap()).collect()
+    }
+
+    #[test]
+    fn redirects_never_downgrade_to_http() {
+        let next = |url| Url::parse(url).unwrap();
+
+        assert!(
+            check_redirect(
+                &urls(&["https://a.example.com"]),
+                &next("https://b.example.com")
+            )
+            .is_ok()
+        );
+        assert!(
+            check_redirect(
+                &urls(&["http://localhost:8200"]),
+                &next("http://localhost:8201")
+            )
+            .is_ok()
+        );
+        assert!(
+            check_redirect(
+                &urls(&["https://a.example.com"]),
+                &next("http://a.example.com")
+            )
+            .is_err()
+        );
+        // an earlier https hop counts as well
+        assert!(
+            check_redirect(
+                &urls(&["https://a.example.com", "https://b.example.com"]),
+                &next("http://c.example.com")
+            )
+            .is_err()
+        );
+        assert!(
+            check_redirect(
+                &vec![next("https://a.example.com"); 11],
+                &next("https://a.example.com")
+            )
+            .is_err()
+        );
+    }
+}
@@ -1,6 +1,10 @@
-use std::sync::Arc;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use error_stack::{Report, ResultExt};
+use rustify::clients::reqwest::Client as HTTPClient;
 use thiserror::Error;
 use tracing::{Level, event, instrument};
 use url::Url;
@@ -9,7 +13,14 @@ use vaultrs::{
     sys::ServerStatus,
 };
 
-use crate::{bitwarden::BitwardenSecret, shoutdown::Shutdown};
+use crate::{
+    conf::{ClusterIdentity, VaultNode},
+    seal::{self, SealStatusResponse},
+    secret::Secret,
+    shoutdown::Shutdown,
+    source::KeySource,
+    tls,
+};
 
 #[allow(clippy::enum_variant_names)]
 #[derive(Debug, Error)]
@@ -20,94 +31,392 @@ pub enum Error {
     ClientSettingError,
     #[error("unseal error")]
     UnsealError,
+    #[error("vault identity mismatch")]
+    IdentityMismatch,
 }
 
 pub type Result<T> = std::result::Result<T, Report<Error>>;
 
+/// An unseal attempt started by this worker, identified by the vault nonce
+struct Attempt {
+    nonce: String,
+    progress: u64,
+    advanced_at: Instant,
+}
+
+impl Attempt {
+    fn observe(&mut self, progress: u64) {
+        if self.progress != progress {
+            self.progress = progress;
+            self.advanced_at = Instant::now();
+        }
+    }
+}
+
 pub struct UnsealWorker {
     client: VaultClient,
-    bitwarden_client: Arc<BitwardenSecret>,
+    source: Arc<dyn KeySource>,
     host: Url,
+    identity: ClusterIdentity,
     interval: u64,
+    stale_progress_timeout: Duration,
+    attempt: Mutex<Option<Attempt>>,
+    partial: bool,
     shoutdown: Arc<Shutdown>,
 }
 
 impl UnsealWorker {
     pub fn new(
-        host: &Url,
+        node: &VaultNode,
         interval: u64,
-        bitwarden_client: Arc<BitwardenSecret>,
+        stale_progress_timeout: u64,
+        source: Arc<dyn KeySource>,
         shoutdown: Arc<Shutdown>,
     ) -> Result<Self> {
-        let client = VaultClient::new(
+        let (address, http) = tls::http_client(&node.host, &node.tls, &node.pins)
+            .change_context(Error::ClientSettingError)?;
+        let mut client = VaultClient::new(
             VaultClientSettingsBuilder::default()
-                .address(host)
+                .address(&address)
                 .build()
                 .change_context(Error::ClientSettingError)?,
         )
         .change_context(Error::ClientError)?;
+        // vaultrs has no knobs for private roots, sni or custom verifiers
+        client.http = HTTPClient::new(address.as_str(), http);
 
         Ok(Self {
             client,
-            bitwarden_client,
-            host: host.clone(),
+            source,
+            host: node.host.clone(),
+            identity: node.identity.clone(),
             interval,
+            stale_progress_timeout: Duration::from_secs(stale_progress_timeout),
+            attempt: Mutex::new(None),
+            partial: false,
             shoutdown,
         })
     }
 
-    async fn get_keys(&self) -> Result<Vec<String>> {
-        let keys = self
-            .bitwarden_client
-            .get_secrets()
+    /// Submit the keys even if they cannot reach the threshold and leave
+    /// progress started elsewhere alone, for shares entered by several
+    /// operators one after another
+    pub fn with_partial_progress(mut self) -> Self {
+        self.partial = true;
+        self
+    }
+
+    // Fetch the keys and the name of the source that provided them
+    async fn get_keys(&self) -> Result<(Vec<Secret>, String)> {
+        let (keys, from) = self
+            .source
+            .fetch_keys_from()
             .await
             .change_context(Error::UnsealError)?;
 
         if keys.is_empty() {
-            event!(Level::WARN, "no unseal keys found from bitwarden");
+            event!(Level::WARN, "no unseal keys found from {}", from);
             let report =
-                Report::new(Error::UnsealError).attach("no unseal keys found from bitwarden");
+                Report::new(Error::UnsealError).attach(format!("no unseal keys found from {from}"));
             return Err(report);
         }
 
-        Ok(keys)
+        Ok((keys, from))
+    }
+
+    // Remember the attempt a submitted share belongs to
+    fn track_attempt(&self, status: &SealStatusResponse) {
+        let mut attempt = self.attempt.lock().unwrap();
+        if let Some(current) = attempt.as_mut().filter(|a| a.nonce == status.nonce) {
+            current.observe(status.progress);
+            return;
+        }
+
+        *attempt = Some(Attempt {
+            nonce: status.nonce.clone(),
+            progress: status.progress,
+            advanced_at: Instant::now(),
+        });
+    }
+
+    // Decide whether partial progress found on the node has to be reset,
+    // returns the reason if so
+    fn stale_progress(&self, status: &SealStatusResponse) -> Option<String> {
+        if status.progress == 0 {
+            return None;
+        }
+
+        let mut attempt = self.attempt.lock().unwrap();
+        match attempt.as_mut().filter(|a| a.nonce == status.nonce) {
+            Some(current) => {
+                current.observe(status.progress);
+
+                let idle = current.advanced_at.elapsed();
+                (idle >= self.stale_progress_timeout)
+                    .then(|| format!("it has not advanced for {}s", idle.as_secs()))
+            }
+            None => Some("it was not started by this worker".to_owned()),
+        }
+    }
+
+    // Refuse to talk to a node that does not match the configured pins
+    fn verify_identity(&self, status: &SealStatusResponse) -> Result<()> {
+        let mut mismatches = Vec::new();
+
+        let pins = [
+            ("cluster_id", &self.identity.cluster_id, &status.cluster_id),
+            (
+                "cluster_name",
+                &self.identity.cluster_name,
+                &status.cluster_name,
+            ),
+        ];
+        for (field, expected, reported) in pins {
+            let Some(expected) = expected else {
+                continue;
+            };
+            match reported.as_deref().filter(|r| !r.is_empty()) {
+                Some(reported) if reported == expected => {}
+                Some(reported) => {
+                    mismatches.push(format!("{field} is {reported}, expected {expected}"));
+                }
+                // sealed nodes never report them, only seal type and
+                // version protect the keys
+                None => {
+                    event!(
+                        Level::DEBUG,
+                        "vault at {} does not report its {}, pin not verified",
+                        self.host,
+                        field
+                    );
+                }
+            }
+        }
+
+        if let Some(expected) = &self.identity.seal_type
+            && *expected != status.seal_type
+        {
+            mismatches.push(format!(
+                "seal type is {}, expected {}",
+                status.seal_type, expected
+            ));
+        }
+
+        if let Some(expected) = &self.identity.version {
+            match semver::Version::parse(&status.version) {
+                Ok(version) if expected.matches(&version) => {}
+                Ok(_) => {
+                    mismatches.push(format!(
+                        "version is {}, expected {}",
+                        status.version, expected
+                    ));
+                }
+                Err(_) => {
+                    mismatches.push(format!("version {} cannot be parsed", status.version));
+                }
+            }
+        }
+
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+
+        event!(
+            Level::ERROR,
+            "refusing to send unseal keys to vault at {}: {}",
+            self.host,
+            mismatches.join(", ")
+        );
+        let report = mismatches.into_iter().fold(
+            Report::new(Error::IdentityMismatch).attach(format!(
+                "vault at {} does not match the configured identity",
+                self.host
+            )),
+            |report, mismatch| report.attach(mismatch),
+        );
+        Err(report)
     }
 
     #[instrument(name = "worker::unseal", skip(self), fields(host = %self.host))]
     async fn unseal(&self) -> Result<()> {
-        let keys = self.get_keys().await?;
+        let status = seal::status(&self.client)
+            .await
+            .change_context(Error::ClientError)
+            .attach(format!(
+                "failed to read seal status of vault at {}",
+                self.host
+            ))?;
 
-        let last_key = keys.last().unwrap().clone();
-        for key in keys.iter().take(keys.len() - 1) {
-            let res = vaultrs::sys::unseal(&self.client, Some(key.clone()), None, None)
-                .await
-                .change_context(Error::ClientError)?;
+        if !status.sealed {
+            event!(Level::INFO, "vault at {} is already unsealed", self.host);
+            *self.attempt.lock().unwrap() = None;
+            return Ok(());
+        }
+
+        if !status.initialized {
+            let report = Report::new(Error::UnsealError)
+                .attach(format!("vault at {} is not initialized", self.host));
+            return Err(report);
+        }
+
+        self.verify_identity(&status)?;
+
+        let (keys, from) = self.get_keys().await?;
+        let available = keys.len() as u64;
 
-            if res.threshold > keys.len() as u64 {
-                let report =
-                    Report::new(Error::UnsealError).attach("not enough keys to unseal the vault");
-                return Err(report);
+        if available > status.shares {
+            let report = Report::new(Error::UnsealError).attach(format!(
+                "{} keys available but vault at {} only has {} key shares",
+                available, self.host, status.shares
+            ));
+            return Err(report);
+        }
+
+        // a reset throws away shares entered by someone else, e.g. with
+        // `vault-unseal manual`, so only reset when these keys alone unseal
+        let status = match self.stale_progress(&status) {
+            Some(reason) if !self.partial && available >= status.threshold => {
+                event!(
+                    Level::WARN,
+                    "resetting unseal progress {}/{} (nonce {}) on vault at {} because {}",
+                    status.progress,
+                    status.threshold,
+                    status.nonce,
+                    self.host,
+                    reason
+                );
+                *self.attempt.lock().unwrap() = None;
+                seal::reset(&self.client)
+                    .await
+                    .change_context(Error::ClientError)
+                    .attach(format!(
+                        "failed to reset unseal progress of vault at {}",
+                        self.host
+                    ))?
+            }
+            Some(reason) if !self.partial => {
+                event!(
+                    Level::WARN,
+                    "keeping unseal progress {}/{} (nonce {}) on vault at {} although {}, {} keys available cannot reach the threshold alone",
+                    status.progress,
+                    status.threshold,
+                    status.nonce,
+                    self.host,
+                    reason,
+                    available
+                );
+                status
+            }
+            _ => status,
+        };
+
+        // only submit the shares still missing from the current attempt
+        let needed = status.threshold.saturating_sub(status.progress);
+        if available < needed && !self.partial {
+            let report = Report::new(Error::UnsealError).attach(format!(
+                "not enough keys to unseal vault at {}: threshold {}, progress {}, {} keys available",
+                self.host, status.threshold, status.progress, available
+            ));
+            return Err(report);
+        }
+
+        event!(
+            Level::DEBUG,
+            "vault at {} needs {} more key shares (threshold {}, progress {})",
+            self.host,
+            needed,
+            status.threshold,
+            status.progress
+        );
+
+        let mut progress = status.progress;
+        let mut submitted = 0;
+        for key in keys.iter() {
+            if submitted == needed {
+                break;
             }
 
+            let res = seal::unseal(&self.client, key.expose())
+                .await
+                .change_context(Error::ClientError)?;
+
             if !res.sealed {
-                event!(Level::INFO, "vault at {} is unsealed", self.host);
+                event!(
+                    Level::INFO,
+                    "vault at {} is unsealed with keys from {}",
+                    self.host,
+                    from
+                );
+                *self.attempt.lock().unwrap() = None;
                 return Ok(());
             }
+
+            self.track_attempt(&res);
+
+            // vault ignores a share it already holds for the current attempt
+            if res.progress <= progress {
+                event!(
+                    Level::DEBUG,
+                    "key share already accepted by vault at {}, skipping",
+                    self.host
+                );
+                continue;
+            }
+
+            progress = res.progress;
+            submitted += 1;
         }
 
-        let res = vaultrs::sys::unseal(&self.client, Some(last_key), None, None)
-            .await
-            .change_context(Error::ClientError)?;
+        if self.partial && submitted < needed {
+            event!(
+                Level::INFO,
+                "vault at {} is still sealed, progress {}/{}",
+                self.host,
+                progress,
+                status.threshold
+            );
+            return Ok(());
+        }
 
-        if res.sealed {
-            let report = Report::new(Error::UnsealError)
-                .attach(format!("failed to unseal the vaule node: {}", self.host));
-            return Err(report);
+        let report = Report::new(Error::UnsealError).attach(format!(
+            "failed to unseal the vault node: {}, progress {}/{} after submitting {} key shares",
+            self.host, progress, status.threshold, submitted
+        ));
+        Err(report)
+    }
+
+    /// Run a single unseal attempt and return the seal status it left behind
+    pub async fn unseal_once(&self, reset: bool) -> Result<SealStatusResponse> {
+        // vault refuses to reset an unsealed node
+        if reset
+            && seal::status(&self.client)
+                .await
+                .change_context(Error::ClientError)?
+                .sealed
+        {
+            event!(
+                Level::WARN,
+                "resetting unseal progress on vault at {}",
+                self.host
+            );
+            seal::reset(&self.client)
+                .await
+                .change_context(Error::ClientError)
+                .attach(format!(
+                    "failed to reset unseal progress of vault at {}",
+                    self.host
+                ))?;
         }
 
-        event!(Level::INFO, "vault at {} is unsealed", self.host);
+        self.unseal().await?;
 
-        Ok(())
+        seal::status(&self.client)
+            .await
+            .change_context(Error::ClientError)
+            .attach(format!(
+                "failed to read seal status of vault at {}",
+                self.host
+            ))
     }
 
     #[instrument(name = "worker::run", skip(self), fields(host = %self.host))]
@@ -118,6 +427,14 @@ impl UnsealWorker {
             self.host
         );
 
+        if self.host.scheme() == "http" {
+            event!(
+                Level::WARN,
+                "vault at {} uses plaintext http, unseal keys are sent in cleartext",
+                self.host
+            );
+        }
+
         let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(self.interval));
 
         loop {
@@ -166,3 +483,222 @@ impl UnsealWorker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::testing::{self, Keys, Request, Stub};
+
+    /// Holds the key shares of the current unseal attempt like vault does,
+    /// a share it already holds does not advance the progress
+    struct FakeVault {
+        threshold: u64,
+        shares: u64,
+        initialized: bool,
+        attempt: Mutex<Vec<String>>,
+        sealed: Mutex<bool>,
+    }
+
+    impl FakeVault {
+        fn new(threshold: u64, shares: u64, held: &[&str]) -> Self {
+            Self {
+                threshold,
+                shares,
+                initialized: true,
+                attempt: Mutex::new(held.iter().map(|share| (*share).to_owned()).collect()),
+                sealed: Mutex::new(true),
+            }
+        }
+
+        fn status(&self) -> Value {
+            let progress = self.attempt.lock().unwrap().len();
+            json!({
+                "type": "shamir",
+                "initialized": self.initialized,
+                "sealed": *self.sealed.lock().unwrap(),
+                "t": self.threshold,
+                "n": self.shares,
+                "progress": progress,
+                "nonce": if progress == 0 { "" } else { "2f3b6c1e" },
+                "version": "1.17.0",
+            })
+        }
+
+        fn respond(&self, request: &Request) -> Vec<u8> {
+            if request.path == "/v1/sys/unseal" {
+                let body: Value = serde_json::from_slice(&request.body).unwrap();
+                let mut attempt = self.attempt.lock().unwrap();
+                if body["reset"] == true {
+                    attempt.clear();
+                } else if let Some(key) = body["key"].as_str()
+                    && !attempt.iter().any(|share| share == key)
+                {
+                    attempt.push(key.to_owned());
+                    if attempt.len() as u64 >= self.threshold {
+                        attempt.clear();
+                        *self.sealed.lock().unwrap() = false;
+                    }
+                }
+            }
+            testing::json(200, &self.status().to_string())
+        }
+    }
+
+    fn serve(vault: FakeVault) -> (Arc<FakeVault>, Stub) {
+        let vault = Arc::new(vault);
+        let stub = Stub::serve({
+            let vault = vault.clone();
+            move |request| vault.respond(request)
+        });
+        (vault, stub)
+    }
+
+    fn worker(stub: &Stub, keys: &[&str]) -> UnsealWorker {
+        let node: VaultNode = stub.url.as_str().parse().unwrap();
+        UnsealWorker::new(
+            &node,
+            1,
+            3600,
+            Arc::new(Keys::new("memory", keys)),
+            Arc::new(Shutdown::new()),
+        )
+        .unwrap()
+    }
+
+    /// Key shares submitted to `sys/unseal` in order
+    fn submitted(stub: &Stub) -> Vec<String> {
+        stub.requests()
+            .iter()
+            .filter(|request| request.path == "/v1/sys/unseal")
+            .filter_map(|request| {
+                let body: Value = serde_json::from_slice(&request.body).unwrap();
+                body["key"].as_str().map(str::to_owned)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn submits_only_the_shares_still_missing() {
+        let (vault, stub) = serve(FakeVault::new(2, 5, &[]));
+        worker(&stub, &["a", "b", "c"]).unseal().await.unwrap();
+        assert_eq!(submitted(&stub), ["a", "b"]);
+        assert!(!*vault.sealed.lock().unwrap());
+
+        // progress entered elsewhere is kept when the keys cannot reach the
+        // threshold alone, only threshold - progress shares are sent
+        let (vault, stub) = serve(FakeVault::new(3, 5, &["x"]));
+        worker(&stub, &["a", "b"]).unseal().await.unwrap();
+        assert_eq!(submitted(&stub), ["a", "b"]);
+        assert!(!*vault.sealed.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn shares_vault_already_holds_are_skipped() {
+        let (vault, stub) = serve(FakeVault::new(3, 5, &["a"]));
+        worker(&stub, &["a", "b", "c"])
+            .with_partial_progress()
+            .unseal()
+            .await
+            .unwrap();
+
+        // "a" does not advance the progress, so "b" and "c" are still sent
+        assert_eq!(submitted(&stub), ["a", "b", "c"]);
+        assert!(!*vault.sealed.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn more_keys_than_shares_are_rejected() {
+        let (_vault, stub) = serve(FakeVault::new(2, 2, &[]));
+        let report = worker(&stub, &["a", "b", "c"]).unseal().await.unwrap_err();
+        assert!(matches!(report.current_context(), Error::UnsealError));
+        assert_eq!(stub.calls(), ["GET /v1/sys/seal-status"]);
+    }
+
+    #[tokio::test]
+    async fn uninitialized_vault_is_not_unsealed() {
+        let (_vault, stub) = serve(FakeVault {
+            initialized: false,
+            ..FakeVault::new(2, 5, &[])
+        });
+        let report = worker(&stub, &["a", "b"]).unseal().await.unwrap_err();
+        assert!(matches!(report.current_context(), Error::UnsealError));
+        assert_eq!(stub.calls(), ["GET /v1/sys/seal-status"]);
+    }
+
+    /// Bodies sent to `sys/unseal` in order
+    fn unseal_bodies(stub: &Stub) -> Vec<Value> {
+        stub.requests()
+            .iter()
+            .filter(|request| request.path == "/v1/sys/unseal")
+            .map(|request| serde_json::from_slice(&request.body).unwrap())
+            .collect()
+    }
+
+    fn is_reset(body: &Value) -> bool {
+        body["reset"] == true && body.get("key").is_none_or(Value::is_null)
+    }
+
+    #[tokio::test]
+    async fn fails_fast_without_enough_keys() {
+        let (_vault, stub) = serve(FakeVault::new(3, 5, &[]));
+        let report = worker(&stub, &["a", "b"]).unseal().await.unwrap_err();
+        assert!(matches!(report.current_context(), Error::UnsealError));
+        assert_eq!(stub.calls(), ["GET /v1/sys/seal-status"]);
+
+        // progress entered elsewhere still leaves two shares missing
+        let (_vault, stub) = serve(FakeVault::new(3, 5, &["x"]));
+        assert!(worker(&stub, &["a"]).unseal().await.is_err());
+        assert_eq!(stub.calls(), ["GET /v1/sys/seal-status"]);
+    }
+
+    #[tokio::test]
+    async fn foreign_progress_is_reset_when_the_keys_reach_the_threshold() {
+        let (vault, stub) = serve(FakeVault::new(2, 5, &["x"]));
+        worker(&stub, &["a", "b"]).unseal().await.unwrap();
+
+        assert_eq!(
+            stub.calls(),
+            [
+                "GET /v1/sys/seal-status",
+                "POST /v1/sys/unseal",
+                "POST /v1/sys/unseal",
+                "POST /v1/sys/unseal",
+            ]
+        );
+        let bodies = unseal_bodies(&stub);
+        assert!(is_reset(&bodies[0]));
+        assert_eq!(bodies[1]["key"], "a");
+        assert_eq!(bodies[2]["key"], "b");
+        assert!(!*vault.sealed.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn foreign_progress_is_kept_when_the_keys_cannot_reach_the_threshold() {
+        let (vault, stub) = serve(FakeVault::new(3, 5, &["x"]));
+        worker(&stub, &["a", "b"]).unseal().await.unwrap();
+
+        let bodies = unseal_bodies(&stub);
+        assert!(!bodies.iter().any(is_reset));
+        assert_eq!(submitted(&stub), ["a", "b"]);
+        assert!(!*vault.sealed.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn partial_progress_is_never_reset() {
+        let (vault, stub) = serve(FakeVault::new(2, 5, &["x"]));
+        worker(&stub, &["a", "b"])
+            .with_partial_progress()
+            .unseal()
+            .await
+            .unwrap();
+
+        assert_eq!(
+            stub.calls(),
+            ["GET /v1/sys/seal-status", "POST /v1/sys/unseal"]
+        );
+        assert_eq!(submitted(&stub), ["a"]);
+        assert!(!*vault.sealed.lock().unwrap());
+    }
+}